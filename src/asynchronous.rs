@@ -0,0 +1,218 @@
+//! Futures-based commands, handlers and transport, enabled by the
+//! `async` feature
+//!
+//! An async action still starts with access to the `State`, but returns
+//! a future which the bot runs alongside everything else. The future
+//! can't borrow the state, so it talks back to the bot through a
+//! `Handle`, from `State::handle`:
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate zpet;
+//! # use futures::FutureExt;
+//! # use zpet::*;
+//! # fn fetch_weather() -> futures::future::Ready<String> { futures::future::ready(String::new()) }
+//! Bot::build("topy", ("topy", "home"))
+//!     .command_async(Shape::order(), Scope::Local, vec!["weather"], |state, notice, _| {
+//!         let handle = state.handle();
+//!         let notice = notice.clone();
+//!         fetch_weather().map(move |report| {
+//!             let _ = handle.reply_to(&notice, &report);
+//!         })
+//!     })
+//!     .run().unwrap();
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{Future, FutureExt, LocalBoxFuture};
+use futures::stream::Stream;
+use futures::task::{self, ArcWake};
+
+use error::Result;
+use handle::Handle;
+use transport::{wait_readable, Transport, POLL_INTERVAL};
+use zephyr::Notice;
+
+/// How often a watcher checks whether its stream is still around
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wakes a task by queueing it and waking the bot's event loop
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+    handle: Handle,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(this: &Arc<TaskWaker>) {
+        this.ready.lock().unwrap().push_back(this.id);
+        // if the bot is gone, so is the task
+        let _ = this.handle.wake();
+    }
+}
+
+/// The futures spawned by a bot, run by its event loop
+pub struct Tasks {
+    tasks: HashMap<usize, LocalBoxFuture<'static, ()>>,
+    next_id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+    handle: Handle,
+}
+
+impl Tasks {
+
+    pub fn new(handle: Handle) -> Tasks {
+        Tasks {
+            tasks: HashMap::new(),
+            next_id: 0,
+            ready: Arc::new(Mutex::new(VecDeque::new())),
+            handle,
+        }
+    }
+
+    /// Starts running a future, from the next pass of the event loop
+    pub fn spawn<F>(&mut self, future: F)
+        where F: Future<Output = ()> + 'static {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.tasks.insert(id, future.boxed_local());
+
+        self.ready.lock().unwrap().push_back(id);
+        let _ = self.handle.wake();
+    }
+
+    /// Polls every task which has been woken. Returns how many
+    /// finished
+    pub fn run_ready(&mut self) -> usize {
+        let ready = self.ready.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+        let mut finished = 0;
+        for id in ready {
+            // a task may be woken several times before it is polled
+            let done = match self.tasks.get_mut(&id) {
+                Some(future) => {
+                    let waker = task::waker(Arc::new(TaskWaker {
+                        id,
+                        ready: self.ready.clone(),
+                        handle: self.handle.clone(),
+                    }));
+                    future.as_mut().poll(&mut Context::from_waker(&waker)).is_ready()
+                },
+                None => false,
+            };
+
+            if done {
+                self.tasks.remove(&id);
+                finished += 1;
+            }
+        }
+        finished
+    }
+
+    /// Number of tasks which have not finished
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+/// A transport as an async stream of incoming notices. Waiting for
+/// the transport happens on a helper thread, so any executor can
+/// drive the stream
+pub struct Notices<T> {
+    transport: T,
+    watching: Arc<AtomicBool>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl<T: Transport> Notices<T> {
+
+    pub fn new(transport: T) -> Notices<T> {
+        Notices {
+            transport,
+            watching: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Wakes the current task once the transport may have a notice
+    fn watch(&self, fd: Option<RawFd>, cx: &mut Context) -> io::Result<()> {
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+
+        // the watcher already running will wake the latest task
+        if self.watching.swap(true, Ordering::SeqCst) {
+            return Ok(())
+        }
+
+        let watching = self.watching.clone();
+        let waker = self.waker.clone();
+        let spawned = thread::Builder::new()
+            .name("zpet-notices".to_string())
+            .spawn(move || {
+                match fd {
+                    // give up once the stream is dropped, which closes
+                    // the descriptor without waking poll
+                    Some(fd) => while Arc::strong_count(&waker) > 1 {
+                        match wait_readable(&[fd], Some(WATCH_INTERVAL)) {
+                            Ok(false) => continue,
+                            _ => break,
+                        }
+                    },
+                    None => thread::sleep(POLL_INTERVAL),
+                }
+                watching.store(false, Ordering::SeqCst);
+                if let Some(waker) = waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            });
+
+        if let Err(e) = spawned {
+            self.watching.store(false, Ordering::SeqCst);
+            return Err(e)
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport + Unpin> Stream for Notices<T> {
+    type Item = Result<Notice>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Notice>>> {
+        let this = self.get_mut();
+        match this.transport.read_timeout(Duration::from_secs(0)) {
+            Ok(Some(notice)) => Poll::Ready(Some(Ok(notice))),
+            Ok(None) => {
+                let fd = this.transport.fd();
+                match this.watch(fd, cx) {
+                    Ok(()) => Poll::Pending,
+                    Err(e) => Poll::Ready(Some(Err(e.into()))),
+                }
+            },
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
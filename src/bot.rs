@@ -2,18 +2,69 @@
 
 use zephyr::*;
 use command::*;
+#[cfg(feature = "async")]
+use asynchronous::Tasks;
+use error::{Error, Result};
+use handle::{Handle, Mailbox, Request};
+#[cfg(feature = "persist")]
+use persist;
+use schedule::{self, Cron, Timers};
+use signal::{self, SignalGuard};
+use store::{Backend, Store};
+use transport::{self, Backoff, Transport, POLL_INTERVAL};
 
-use std::mem;
-use std::thread;
-use std::time::Duration;
+use std::cmp;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::time::{Duration, Instant};
 use std::cell::{Ref, RefCell};
 
+/// Changes in the state of a bot and its connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Connected,
+    Disconnected,
+    Reconnected,
+    /// The bot is about to stop, while it can still send notices
+    Shutdown,
+}
+
+/// An action run when an `Event` occurs
+pub type EventAction<E> = Box<dyn Fn(&mut State<E>, Event)>;
+
+/// Most notices handled in one pass of the event loop, so that a flood
+/// of them can't hold up timers and requests from handles
+const MAX_BATCH: usize = 64;
+
+/// How long a connection must last, if no notice arrives on it, before
+/// losing it again starts the backoff over
+const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+
+/// How often a bot with `Builder::persist_to` saves its state
+#[cfg(feature = "persist")]
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Saves a bot's state, as set up by `Builder::persist_to`
+#[cfg(feature = "persist")]
+pub type SaveAction<E> = Box<dyn Fn(&State<E>) -> Result<()>>;
+
 /// Represents a bot
 pub struct Bot<E = ()> {
     pub state: State<E>,
     pub commands: Vec<Command<E>>,
     pub pre_command_handlers: Vec<Handler<E>>,
     pub post_command_handlers: Vec<Handler<E>>,
+    pub event_handlers: Vec<EventAction<E>>,
+    /// Wrapped around the handling of every notice, outermost first
+    pub layers: Vec<Layer<E>>,
+    /// Run around every command and handler, outermost first
+    pub middleware: Vec<Box<dyn Middleware<E>>>,
+    pub backoff: Backoff,
+    /// Whether `run` shuts down on SIGINT and SIGTERM
+    pub catch_signals: bool,
+    mailbox: Mailbox,
+    /// When the bot last reconnected, until a notice arrives
+    reconnected_at: Option<Instant>,
 }
 
 impl Bot {
@@ -24,73 +75,301 @@ impl Bot {
 
 impl<E> Bot<E> {
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: &str,
         class: &str,
         instance: &str,
-        zsig_func: Box<Fn() -> String>,
+        zsig_func: Box<dyn Fn() -> String>,
         extra: E,
-        subs: Vec<Triplet>,
-        commands: Vec<Command<E>>,
-        pre_command_handlers: Vec<Handler<E>>,
-        post_command_handlers: Vec<Handler<E>>,
-    ) -> Bot<E> {
-        Bot {
+        zio: Box<dyn Transport>,
+        mut commands: Vec<Command<E>>,
+        mut pre_command_handlers: Vec<Handler<E>>,
+        mut post_command_handlers: Vec<Handler<E>>,
+    ) -> Result<Bot<E>> {
+        // stable, so equal priorities keep their order
+        commands.sort_by_key(|c| cmp::Reverse(c.priority()));
+        pre_command_handlers.sort_by_key(|h| cmp::Reverse(h.priority));
+        post_command_handlers.sort_by_key(|h| cmp::Reverse(h.priority));
+
+        let mailbox = Mailbox::new()?;
+        Ok(Bot {
             state: State {
                 name: name.to_string(),
+                referent: Referent::default(),
+                lexicon: Lexicon::default(),
                 class: class.to_string(),
                 instance: instance.to_string(),
                 zsig_func,
                 extra,
-                zio: RefCell::new(Zephyr::new(subs).expect("failed to connect to Zephyr"))
+                zio: RefCell::new(zio),
+                timers: Timers::new(),
+                handle: mailbox.handle(),
+                shutting_down: false,
+                store: Store::default(),
+                #[cfg(feature = "persist")]
+                saver: None,
+                #[cfg(feature = "async")]
+                tasks: Tasks::new(mailbox.handle()),
             },
             commands,
             pre_command_handlers,
-            post_command_handlers
+            post_command_handlers,
+            event_handlers: vec![],
+            layers: vec![],
+            middleware: vec![],
+            backoff: Backoff::default(),
+            catch_signals: true,
+            mailbox,
+            reconnected_at: None,
+        })
+    }
+
+    /// A handle for other threads to send requests to this bot
+    pub fn handle(&self) -> Handle {
+        self.mailbox.handle()
+    }
+
+    /// Runs the bot until it is shut down, by `State::shutdown`,
+    /// `Handle::shutdown`, or a signal. Fires `Event::Shutdown`, then
+    /// closes the transport
+    pub fn run(&mut self) -> Result<()> {
+        let _signals = if self.catch_signals {
+            Some(SignalGuard::install(self.mailbox.waker_fd())?)
+        } else {
+            None
+        };
+
+        self.fire(Event::Connected);
+        while !self.stopping() {
+            match self.run_once() {
+                Ok(()) => {},
+                Err(ref e) if e.is_disconnect() => {
+                    eprintln!("lost connection: {}", e);
+                    self.reconnect();
+                },
+                Err(e) => eprintln!("{}", e),
+            }
         }
+
+        self.fire(Event::Shutdown);
+        self.state.zio.borrow_mut().close()
     }
 
-    pub fn run(&mut self) {
+    /// Re-establishes a lost connection, retrying with exponential
+    /// backoff until it succeeds or the bot is shut down. The backoff
+    /// starts over only once a connection has proven healthy, by a
+    /// notice arriving or by lasting `HEALTHY_UPTIME`; losing one
+    /// before then waits out the next delay first
+    pub fn reconnect(&mut self) {
+        self.fire(Event::Disconnected);
+
+        match self.reconnected_at {
+            Some(at) if at.elapsed() < HEALTHY_UPTIME => {
+                let delay = self.backoff.next_delay();
+                eprintln!("connection did not last, reconnecting in {:?}", delay);
+                self.pause(delay);
+                if self.stopping() {
+                    return
+                }
+            },
+            _ => self.backoff.reset(),
+        }
+
         loop {
-            match {
-                let mut zio = self.state.zio.borrow_mut();
-                let notice = zio.read();
-                drop(zio);
-                notice
-            } {
-                Ok(notice) => {
-                    self.tick(notice);
-                    thread::sleep(Duration::from_millis(100))
+            let result = self.state.zio.borrow_mut().reconnect();
+            match result {
+                Ok(()) => break,
+                Err(e) => {
+                    let delay = self.backoff.next_delay();
+                    eprintln!("failed to reconnect: {}, retrying in {:?}", e, delay);
+                    self.pause(delay);
+                    if self.stopping() {
+                        return
+                    }
                 },
-                Err(e) => eprintln!("{:?}", e),
             }
+        }
 
+        self.reconnected_at = Some(Instant::now());
+        self.fire(Event::Reconnected);
+    }
+
+    /// Whether the bot has been asked to shut down
+    fn stopping(&mut self) -> bool {
+        if self.catch_signals && signal::take_caught() {
+            self.state.shutdown();
         }
+        self.state.is_shutting_down()
     }
 
-    pub fn tick(&mut self, notice: Notice) {
+    /// Sleeps for `delay`, but still carries out requests from
+    /// handles, and stops early if the bot is shut down
+    fn pause(&mut self, delay: Duration) {
+        let deadline = Instant::now() + delay;
+        while !self.stopping() {
+            let now = Instant::now();
+            if now >= deadline {
+                break
+            }
+            if let Ok(true) = transport::wait_readable(&[self.mailbox.fd()], Some(deadline - now)) {
+                if let Err(e) = self.run_requests() {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
 
-        if notice.opcode == "AUTO" {
-            return
+    fn fire(&mut self, event: Event) {
+        for action in self.event_handlers.iter() {
+            action(&mut self.state, event);
         }
+    }
 
-        for hdl in self.pre_command_handlers.iter() {
-            if hdl.try_exec(&mut self.state, &notice) {
-                return
-            }
+    /// Reads a single notice from the transport and handles it
+    pub fn step(&mut self) -> Result<()> {
+        let notice = self.state.zio.borrow_mut().read()?;
+        self.reconnected_at = None;
+        self.tick(notice);
+        Ok(())
+    }
+
+    /// Like `step`, but waits at most `timeout` for a notice. Returns
+    /// whether one was handled
+    pub fn step_timeout(&mut self, timeout: Duration) -> Result<bool> {
+        let notice = self.state.zio.borrow_mut().read_timeout(timeout)?;
+        match notice {
+            Some(notice) => {
+                self.reconnected_at = None;
+                self.tick(notice);
+                Ok(true)
+            },
+            None => Ok(false),
         }
+    }
+
+    /// Runs every timer which is due
+    pub fn run_timers(&mut self) {
+        schedule::run_due(&mut self.state);
+    }
 
-        for cmd in self.commands.iter() {
-            if cmd.try_exec(&mut self.state, &notice) {
-                return
+    /// Carries out every request sent through a `Handle` so far
+    pub fn run_requests(&mut self) -> Result<()> {
+        for request in self.mailbox.take()? {
+            let result = match request {
+                Request::Inject(notice) => {
+                    self.tick(notice);
+                    Ok(())
+                },
+                Request::Send(notice) => self.state.zwrite(&notice),
+                Request::Reply(triplet, body) => self.state.reply_at(&triplet, &body),
+                Request::Shutdown => {
+                    self.state.shutdown();
+                    Ok(())
+                },
+            };
+            if let Err(e) = result {
+                if e.is_disconnect() {
+                    return Err(e)
+                }
+                eprintln!("{}", e);
             }
         }
+        Ok(())
+    }
+
+    /// Polls every spawned future which is ready to make progress
+    #[cfg(feature = "async")]
+    pub fn run_tasks(&mut self) {
+        self.state.tasks.run_ready();
+    }
+
+    /// One pass of the event loop: handles everything which is ready,
+    /// then waits until the transport, a timer, or a handle has more
+    pub fn run_once(&mut self) -> Result<()> {
+        self.run_timers();
+        self.run_requests()?;
+        #[cfg(feature = "async")]
+        self.run_tasks();
 
-        for hdl in self.post_command_handlers.iter() {
-            if hdl.try_exec(&mut self.state, &notice) {
-                return
+        for _ in 0..MAX_BATCH {
+            if self.state.is_shutting_down() {
+                return Ok(())
+            }
+            if !self.step_timeout(Duration::from_secs(0))? {
+                return self.wait()
             }
         }
+        Ok(())
+    }
+
+    /// Waits until there may be something to handle
+    fn wait(&mut self) -> Result<()> {
+        let timeout = self.state.timers.next_due()
+            .map(|due| due.saturating_duration_since(Instant::now()));
+
+        let fd = self.state.zio.borrow().fd();
+        match fd {
+            Some(fd) => transport::wait_readable(&[fd, self.mailbox.fd()], timeout)?,
+            None => {
+                let timeout = cmp::min(timeout.unwrap_or(POLL_INTERVAL), POLL_INTERVAL);
+                transport::wait_readable(&[self.mailbox.fd()], Some(timeout))?
+            },
+        };
+        Ok(())
+    }
+
+    /// Handles a notice: pre handlers, then commands, then post
+    /// handlers, each in order of priority, until one returns anything
+    /// but `Dispatch::Continue`. Returns what that was
+    pub fn tick(&mut self, notice: Notice) -> Dispatch {
+
+        if notice.opcode == "AUTO" {
+            return Dispatch::Continue
+        }
+
+        let pre = &self.pre_command_handlers;
+        let commands = &self.commands;
+        let post = &self.post_command_handlers;
+        let middleware = &self.middleware;
+        let pipeline = |state: &mut State<E>, notice: &Notice| {
+            for hdl in pre.iter() {
+                let result = hdl.try_exec_with(middleware, state, notice);
+                if result.is_final() {
+                    return result
+                }
+            }
+
+            for cmd in commands.iter() {
+                let result = cmd.try_exec_with(middleware, state, notice);
+                if result.is_final() {
+                    return result
+                }
+            }
+
+            for hdl in post.iter() {
+                let result = hdl.try_exec_with(middleware, state, notice);
+                if result.is_final() {
+                    return result
+                }
+            }
+            Dispatch::Continue
+        };
+
+        run_layers(&self.layers, &pipeline, &mut self.state, &notice)
+    }
+}
+
+/// Calls the rest of the pipeline from a `Layer`
+pub type Next<'a, E> = &'a dyn Fn(&mut State<E>, &Notice) -> Dispatch;
+
+/// Wraps the handling of a notice. See `Builder::wrap`
+pub type Layer<E> = Box<dyn Fn(&mut State<E>, &Notice, Next<'_, E>) -> Dispatch>;
+
+fn run_layers<E>(layers: &[Layer<E>], pipeline: Next<'_, E>, state: &mut State<E>, notice: &Notice) -> Dispatch {
+    match layers.split_first() {
+        Some((layer, rest)) => layer(state, notice, &|state, notice| run_layers(rest, pipeline, state, notice)),
+        None => pipeline(state, notice),
     }
 }
 
@@ -98,50 +377,81 @@ impl<E> Bot<E> {
 /// to share state
 pub struct State<E> {
     pub name: String,
+    /// Other ways commands may refer to the bot
+    pub referent: Referent,
+    /// Other ways commands may be named, including translations
+    pub lexicon: Lexicon,
     pub class: String,
     pub instance: String,
-    zsig_func: Box<Fn() -> String>,
+    zsig_func: Box<dyn Fn() -> String>,
     extra: E,
-    zio: RefCell<Zephyr>,
+    zio: RefCell<Box<dyn Transport>>,
+    timers: Timers<E>,
+    handle: Handle,
+    shutting_down: bool,
+    store: Store,
+    #[cfg(feature = "async")]
+    tasks: Tasks,
+    #[cfg(feature = "persist")]
+    saver: Option<SaveAction<E>>,
 }
 
 impl<E> State<E> {
 
-    pub fn subs(&self) -> Ref<Vec<Triplet>> {
+    pub fn subs(&self) -> Ref<'_, Vec<Triplet>> {
         Ref::map(self.zio.borrow(), |x| x.subs())
     }
 
-    pub fn zwrite(&self, notice: &Notice) {
-        self.zio.borrow_mut().zwrite(&notice).expect("unable to send zephyr")
+    /// Starts listening on a triplet, in addition to the current
+    /// subscriptions
+    pub fn subscribe(&self, triplet: Triplet) -> Result<()> {
+        self.zio.borrow_mut().subscribe(triplet)
     }
 
-    pub fn reply_here(&self, body: &str) {
+    /// Stops listening on a triplet
+    pub fn unsubscribe(&self, triplet: &Triplet) -> Result<()> {
+        self.zio.borrow_mut().unsubscribe(triplet)
+    }
+
+    pub fn zwrite(&self, notice: &Notice) -> Result<()> {
+        self.zio.borrow_mut().send(notice)
+    }
+
+    pub fn reply_here(&self, body: &str) -> Result<()> {
         self.reply_at(&self.location(), body)
     }
 
-    pub fn reply_here_zsigned(&self, zsig: &str, body: &str) {
+    pub fn reply_here_zsigned(&self, zsig: &str, body: &str) -> Result<()> {
         self.reply_at_zsigned(&self.location(), zsig, body)
     }
 
-    pub fn reply_to(&self, notice: &Notice, body: &str) {
-        self.reply_at(&notice.triplet(), body)
+    pub fn reply_to(&self, notice: &Notice, body: &str) -> Result<()> {
+        self.reply_at(&notice.reply_triplet(), body)
+    }
+
+    pub fn reply_to_zsigned(&self, notice: &Notice, zsig: &str, body: &str) -> Result<()> {
+        self.reply_at_zsigned(&notice.reply_triplet(), zsig, body)
     }
 
-    pub fn reply_to_zsigned(&self, notice: &Notice, zsig: &str, body: &str) {
-        self.reply_at_zsigned(&notice.triplet(), zsig, body)
+    pub fn reply_personal(&self, user: &str, body: &str) -> Result<()> {
+        self.reply_at(&Triplet::to_user(user), body)
     }
 
-    pub fn reply_at(&self, triplet: &Triplet, body: &str) {
+    pub fn reply_personal_zsigned(&self, user: &str, zsig: &str, body: &str) -> Result<()> {
+        self.reply_at_zsigned(&Triplet::to_user(user), zsig, body)
+    }
+
+    pub fn reply_at(&self, triplet: &Triplet, body: &str) -> Result<()> {
         self.reply_at_zsigned(triplet, &(self.zsig_func)(), body)
     }
 
-    pub fn reply_at_zsigned(&self, triplet: &Triplet, zsig: &str, body: &str) {
+    pub fn reply_at_zsigned(&self, triplet: &Triplet, zsig: &str, body: &str) -> Result<()> {
         let reply = triplet.make_reply(
             &self.name,
             zsig,
             body
         );
-        self.zwrite(&reply);
+        self.zwrite(&reply)
     }
 
     pub fn location(&self) -> Triplet {
@@ -150,7 +460,65 @@ impl<E> State<E> {
 
     pub fn move_to(&mut self, to: Triplet) {
         self.class = to.class;
-        self.instance = to.instance.unwrap_or("personal".to_string());
+        self.instance = to.instance.unwrap_or_else(|| "personal".to_string());
+    }
+
+    /// Runs an action once, after `delay` has passed
+    pub fn schedule_after<F>(&mut self, delay: Duration, action: F)
+        where F: FnOnce(&mut State<E>) + 'static {
+        self.timers.after(delay, Box::new(action));
+    }
+
+    /// Number of timers waiting to run, including repeating ones
+    pub fn scheduled(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Asks the bot to stop once the current notice is handled
+    pub fn shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    /// Saves the bot's state now, rather than waiting for the next
+    /// periodic save. Does nothing without `Builder::persist_to`
+    #[cfg(feature = "persist")]
+    pub fn save(&self) -> Result<()> {
+        match self.saver {
+            Some(ref saver) => saver(self),
+            None => Ok(()),
+        }
+    }
+
+    /// Key-value storage for users, triplets, and the bot as a whole
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// A handle to this bot, for use from futures and other threads
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Runs a future alongside the bot's other work. It can talk to
+    /// the bot through `handle`
+    #[cfg(feature = "async")]
+    pub fn spawn<F>(&mut self, future: F)
+        where F: Future<Output = ()> + 'static {
+        self.tasks.spawn(future);
+    }
+
+    /// Number of spawned futures which have not finished
+    #[cfg(feature = "async")]
+    pub fn spawned(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub(crate) fn timers_mut(&mut self) -> &mut Timers<E> {
+        &mut self.timers
     }
 
     pub fn extra_ref(&self) -> &E {
@@ -163,120 +531,610 @@ impl<E> State<E> {
 }
 
 pub mod builder {
+    //! Builder for bots. The type of `State::extra` must be chosen
+    //! with `with_extra` before any commands or handlers, which take
+    //! a `State<E>`, are added; the compiler enforces this ordering:
+    //!
+    //! ```compile_fail
+    //! # use zpet::*;
+    //! Bot::build("topy", ("topy", "home"))
+    //!     .pre(|_, _| false)
+    //!     .with_extra(0u32);
+    //! ```
 
     use super::*;
 
+    use std::marker::PhantomData;
+    #[cfg(feature = "persist")]
+    use std::path::Path;
+
+    #[cfg(feature = "persist")]
+    use serde::Serialize;
+    #[cfg(feature = "persist")]
+    use serde::de::DeserializeOwned;
+
     use rand;
     use rand::Rng;
 
-    pub struct Builder<E = ()> {
+    /// Stage of a builder which has no commands or handlers yet,
+    /// so its extra state may still be replaced
+    pub struct NoHandlers;
+
+    /// Stage of a builder with commands or handlers for its extra state
+    pub struct WithHandlers;
+
+    /// Settings which do not depend on the extra state
+    struct Config {
         name: String,
         class: String,
         instance: String,
-        zsig_func: Box<Fn() -> String>,
-        extra: Box<E>,
+        zsig_func: Box<dyn Fn() -> String>,
         subs: Vec<Triplet>,
+        transport: Option<Box<dyn Transport>>,
+        backoff: Backoff,
+        catch_signals: bool,
+        referent: Referent,
+        lexicon: Lexicon,
+        help: bool,
+        store: Option<Box<dyn Backend>>,
+        /// The first invalid setting, reported by `build`
+        error: Option<Error>,
+    }
+
+    impl Config {
+
+        /// Records an invalid setting, unless an earlier one was
+        fn fail(&mut self, e: Error) {
+            if self.error.is_none() {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Everything which takes a `State<E>`
+    struct Handlers<E> {
         commands: Vec<Command<E>>,
         pre_command_handlers: Vec<Handler<E>>,
         post_command_handlers: Vec<Handler<E>>,
+        event_handlers: Vec<EventAction<E>>,
+        layers: Vec<Layer<E>>,
+        middleware: Vec<Box<dyn Middleware<E>>>,
+        timers: Timers<E>,
+        /// What `Builder::priority` applies to
+        last: Option<Added>,
+        #[cfg(feature = "persist")]
+        persist: Option<(SaveAction<E>, LoadAction<E>)>,
     }
 
-    impl Builder {
-        pub fn new(name: &str, start: (&str, &str)) -> Builder {
-            Builder {
-                name: name.to_string(),
-                class: start.0.to_string(),
-                instance: start.1.to_string(),
-                zsig_func: Box::new(|| "".to_string()),
-                extra: Box::new(()),
-                subs: vec![],
+    enum Added {
+        Command,
+        Pre,
+        Post,
+    }
+
+    #[cfg(feature = "persist")]
+    type LoadAction<E> = Box<dyn Fn(&mut State<E>) -> Result<()>>;
+
+    impl<E> Handlers<E> {
+        fn new() -> Handlers<E> {
+            Handlers {
                 commands: vec![],
                 pre_command_handlers: vec![],
                 post_command_handlers: vec![],
+                event_handlers: vec![],
+                layers: vec![],
+                middleware: vec![],
+                timers: Timers::new(),
+                last: None,
+                #[cfg(feature = "persist")]
+                persist: None,
             }
         }
     }
 
-    impl<E> Builder<E> {
+    pub struct Builder<E = (), S = NoHandlers> {
+        config: Config,
+        extra: E,
+        handlers: Handlers<E>,
+        stage: PhantomData<S>,
+    }
 
-        pub fn with_zsig(mut self, zsig: &str) -> Builder<E> {
+    impl Builder {
+        pub fn new(name: &str, start: (&str, &str)) -> Builder {
+            Builder {
+                config: Config {
+                    name: name.to_string(),
+                    class: start.0.to_string(),
+                    instance: start.1.to_string(),
+                    zsig_func: Box::new(|| "".to_string()),
+                    subs: vec![],
+                    transport: None,
+                    backoff: Backoff::default(),
+                    catch_signals: true,
+                    referent: Referent::default(),
+                    lexicon: Lexicon::default(),
+                    help: false,
+                    store: None,
+                    error: None,
+                },
+                extra: (),
+                handlers: Handlers::new(),
+                stage: PhantomData,
+            }
+        }
+    }
+
+    impl<E> Builder<E, NoHandlers> {
+
+        /// Replaces the extra state, changing its type
+        pub fn with_extra<E2>(self, extra: E2) -> Builder<E2, NoHandlers> {
+            Builder {
+                config: self.config,
+                extra,
+                handlers: Handlers::new(),
+                stage: PhantomData,
+            }
+        }
+    }
+
+    impl<E, S> Builder<E, S> {
+
+        pub fn with_zsig(mut self, zsig: &str) -> Builder<E, S> {
             let owned = zsig.to_string();
-            self.zsig_func = Box::new(move || owned.clone());
+            self.config.zsig_func = Box::new(move || owned.clone());
             self
         }
 
-        pub fn with_zsigs(mut self, zsigs: Vec<&str>) -> Builder<E> {
-            assert!(!zsigs.is_empty());
+        /// Signs each notice with one of `zsigs` at random. No zsigs
+        /// at all makes `build` fail
+        pub fn with_zsigs(mut self, zsigs: Vec<&str>) -> Builder<E, S> {
+            if zsigs.is_empty() {
+                self.config.fail(Error::Config("with_zsigs needs at least one zsig".to_string()));
+                return self
+            }
             let owned = zsigs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
-            self.zsig_func = Box::new(move || rand::thread_rng().choose(&owned).unwrap().clone());
+            self.config.zsig_func = Box::new(move || rand::thread_rng().choose(&owned).unwrap().clone());
             self
         }
 
-        pub fn zsig_fn<F>(mut self, f: F) -> Builder<E>
+        pub fn zsig_fn<F>(mut self, f: F) -> Builder<E, S>
             where F: Fn() -> String + 'static {
-            self.zsig_func = Box::new(f);
+            self.config.zsig_func = Box::new(f);
+            self
+        }
+
+        pub fn sub_to_class(mut self, class: &str) -> Builder<E, S> {
+            self.config.subs.push(Triplet::of_class(class));
+            self
+        }
+
+        pub fn sub_to_classes(mut self, classes: Vec<&str>) -> Builder<E, S> {
+            self.config.subs.append(&mut classes.iter().map(|c| Triplet::of_class(c)).collect::<Vec<_>>());
+            self
+        }
+
+        pub fn sub_to_personals(mut self) -> Builder<E, S> {
+            self.config.subs.push(Triplet::personal());
+            self
+        }
+
+        pub fn sub_to(mut self, mut triplets: Vec<Triplet>) -> Builder<E, S> {
+            self.config.subs.append(&mut triplets);
+            self
+        }
+
+        /// Use the given transport instead of spawning zwgc. Subscriptions
+        /// added to this builder are applied to it on build
+        pub fn transport<T>(mut self, transport: T) -> Builder<E, S>
+            where T: Transport + 'static {
+            self.config.transport = Some(Box::new(transport));
+            self
+        }
+
+        /// Sets the delays between attempts to reconnect, which start at
+        /// `initial` and double up to `max`
+        pub fn reconnect_backoff(mut self, initial: Duration, max: Duration) -> Builder<E, S> {
+            self.config.backoff = Backoff::new(initial, max);
+            self
+        }
+
+        /// Whether `run` shuts down on SIGINT and SIGTERM, which it
+        /// does by default
+        pub fn catch_signals(mut self, catch: bool) -> Builder<E, S> {
+            self.config.catch_signals = catch;
+            self
+        }
+
+        /// Answers commands addressed to `alias` as well as the bot's
+        /// name
+        pub fn alias(mut self, alias: &str) -> Builder<E, S> {
+            self.config.referent.aliases.push(alias.to_string());
+            self
+        }
+
+        /// Whether commands may address the bot in any case, as in
+        /// "Topy, sit!"
+        pub fn referent_ignore_case(mut self, ignore: bool) -> Builder<E, S> {
+            self.config.referent.ignore_case = ignore;
+            self
+        }
+
+        /// How many typos to forgive in the bot's name or aliases
+        pub fn referent_typos(mut self, typos: usize) -> Builder<E, S> {
+            self.config.referent.typos = typos;
+            self
+        }
+
+        /// Makes `words` mean the command label `label`
+        pub fn synonyms(mut self, label: &str, words: Vec<&str>) -> Builder<E, S> {
+            self.config.lexicon.synonyms(label, words);
+            self
+        }
+
+        /// Makes `words` mean the command label `label` when the bot
+        /// speaks `locale`
+        pub fn translate(mut self, locale: &str, label: &str, words: Vec<&str>) -> Builder<E, S> {
+            self.config.lexicon.translate(locale, label, words);
             self
         }
 
-        pub fn sub_to_class(mut self, class: &str) -> Builder<E> {
-            self.subs.push(Triplet::of_class(class));
+        /// Which translations the bot starts with. See `Lexicon::locale`
+        pub fn locale(mut self, locale: &str) -> Builder<E, S> {
+            self.config.lexicon.locale = Some(locale.to_string());
             self
         }
 
-        pub fn sub_to_classes(mut self, classes: Vec<&str>) -> Builder<E> {
-            self.subs.append(&mut classes.iter().map(|c| Triplet::of_class(c)).collect::<Vec<_>>());
+        /// Whether command labels may be written in any case
+        pub fn labels_ignore_case(mut self, ignore: bool) -> Builder<E, S> {
+            self.config.lexicon.ignore_case = ignore;
             self
         }
 
-        pub fn sub_to(mut self, mut triplets: Vec<Triplet>) -> Builder<E> {
-            self.subs.append(&mut triplets);
+        /// Whether command labels may be written in other English forms,
+        /// as in "topy pets" for "pet"
+        pub fn labels_stem(mut self, stem: bool) -> Builder<E, S> {
+            self.config.lexicon.stem = stem;
             self
         }
 
-        pub fn command<F>(mut self, shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Builder<E>
-            where F: Fn(&mut State<E>, &Notice, &CommandMatch) -> () + 'static {
-            self.commands.push(Command::new(shape, scope, labels, action));
+        /// Adds a `help` command, answering with every other command.
+        /// See `Command::help`
+        pub fn help(mut self) -> Builder<E, S> {
+            self.config.help = true;
             self
         }
 
-        pub fn pre<F>(mut self, action: F) -> Builder<E>
-            where F: Fn(&mut State<E>, &Notice) -> bool + 'static {
-            self.pre_command_handlers.push(Handler::new(action));
+        /// Describes the last command added, for `help`. Ignored if a
+        /// handler was added since
+        pub fn describe(mut self, description: &str) -> Builder<E, S> {
+            self.map_last_command(|command| command.describe(description));
             self
         }
 
-        pub fn post<F>(mut self, action: F) -> Builder<E>
-            where F: Fn(&mut State<E>, &Notice) -> bool + 'static {
-            self.post_command_handlers.push(Handler::new(action));
+        /// Gives an example of the last command added, for `help`, as
+        /// `describe`
+        pub fn example(mut self, example: &str) -> Builder<E, S> {
+            self.map_last_command(|command| command.with_example(example));
             self
         }
 
-        pub fn with_extra<E2>(mut self, extra: E2) -> Builder<E2> {
-            let mut extra_box = Box::new(extra);
-            unsafe {
-                let mut new_builder: Builder<E2> = mem::transmute(self);
-                mem::swap(&mut new_builder.extra, &mut extra_box);
-                drop(mem::transmute::<Box<E2>, Box<E>>(extra_box));
-                new_builder
+        fn map_last_command<F>(&mut self, f: F)
+            where F: FnOnce(Command<E>) -> Command<E> {
+            if let Some(Added::Command) = self.handlers.last {
+                if let Some(command) = self.handlers.commands.pop() {
+                    self.handlers.commands.push(f(command));
+                }
             }
         }
 
-        pub fn build(self) -> Bot<E> {
-            Bot::new(
-                &self.name,
-                &self.class,
-                &self.instance,
-                self.zsig_func,
-                *self.extra,
-                self.subs,
-                self.commands,
-                self.pre_command_handlers,
-                self.post_command_handlers
-            )
+        /// Keeps `State::store` in the given backend, rather than in
+        /// memory
+        pub fn store<B>(mut self, backend: B) -> Builder<E, S>
+            where B: Backend + 'static {
+            self.config.store = Some(Box::new(backend));
+            self
+        }
+
+        /// Adds a command. The action may return nothing, meaning the
+        /// command handled the notice, or a `Dispatch`
+        pub fn command<F, R>(self, shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice, &CommandMatch) -> R + 'static,
+                  R: Into<Dispatch> {
+            let mut builder = self.armed();
+            builder.handlers.commands.push(Command::new(shape, scope, labels, action));
+            builder.handlers.last = Some(Added::Command);
+            builder
+        }
+
+        /// Like `command`, but the action gets its arguments already
+        /// parsed. If they don't parse, the sender is told why, and how
+        /// the command is used
+        pub fn command_typed<A, F, R>(self, shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Builder<E, WithHandlers>
+            where A: FromArgs,
+                  F: Fn(&mut State<E>, &Notice, A) -> R + 'static,
+                  R: Into<Dispatch> {
+            let usage = shape.clone();
+            self.command(shape, scope, labels, move |state, notice, cm| match cm.parse() {
+                Ok(args) => action(state, notice, args).into(),
+                Err(e) => {
+                    let body = format!("{}\nusage: {}", e, usage.usage(cm.referent, cm.command).join("\n       "));
+                    if let Err(e) = state.reply_personal(&notice.sender, &body) {
+                        eprintln!("failed to send usage: {}", e);
+                    }
+                    Dispatch::Handled
+                },
+            })
+        }
+
+        /// Adds a handler run before any command. The action may
+        /// return whether it handled the notice, or a `Dispatch`
+        pub fn pre<F, R>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice) -> R + 'static,
+                  R: Into<Dispatch> {
+            let mut builder = self.armed();
+            builder.handlers.pre_command_handlers.push(Handler::new(action));
+            builder.handlers.last = Some(Added::Pre);
+            builder
         }
 
-        pub fn run(self) {
-            self.build().run()
+        /// Adds a handler run after every command, as `pre`
+        pub fn post<F, R>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice) -> R + 'static,
+                  R: Into<Dispatch> {
+            let mut builder = self.armed();
+            builder.handlers.post_command_handlers.push(Handler::new(action));
+            builder.handlers.last = Some(Added::Post);
+            builder
+        }
+
+        /// Sets the priority of the last command or handler added. See
+        /// `Command::with_priority`
+        pub fn priority(mut self, priority: i32) -> Builder<E, S> {
+            let handlers = &mut self.handlers;
+            match handlers.last {
+                Some(Added::Command) => self.map_last_command(|command| command.with_priority(priority)),
+                Some(Added::Pre) => handlers.pre_command_handlers.last_mut().unwrap().priority = priority,
+                Some(Added::Post) => handlers.post_command_handlers.last_mut().unwrap().priority = priority,
+                None => {},
+            }
+            self
+        }
+
+        /// Wraps the whole of handling a notice, from pre handlers to
+        /// post handlers. The action calls `next` to carry on, or
+        /// doesn't to skip it. The first layer added is the outermost.
+        /// Like handlers, layers must come after `with_extra`:
+        ///
+        /// ```compile_fail
+        /// # use zpet::*;
+        /// Bot::build("topy", ("topy", "home"))
+        ///     .wrap(|state, notice, next| next(state, notice))
+        ///     .with_extra(0u32);
+        /// ```
+        pub fn wrap<F>(self, layer: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice, Next<'_, E>) -> Dispatch + 'static {
+            let mut builder = self.armed();
+            builder.handlers.layers.push(Box::new(layer));
+            builder
+        }
+
+        /// Runs `middleware` around every handler, and every command
+        /// which matches a notice. The first added is the outermost.
+        /// Like handlers, middleware must come after `with_extra`:
+        ///
+        /// ```compile_fail
+        /// # use zpet::*;
+        /// # use zpet::command::Middleware;
+        /// struct Quiet;
+        /// impl Middleware<()> for Quiet {}
+        ///
+        /// Bot::build("topy", ("topy", "home"))
+        ///     .middleware(Quiet)
+        ///     .with_extra(0u32);
+        /// ```
+        pub fn middleware<M>(self, middleware: M) -> Builder<E, WithHandlers>
+            where M: Middleware<E> + 'static {
+            let mut builder = self.armed();
+            builder.handlers.middleware.push(Box::new(middleware));
+            builder
+        }
+
+        /// Like `command`, but the action returns a future which runs
+        /// alongside the bot, so that slow work doesn't hold it up
+        #[cfg(feature = "async")]
+        pub fn command_async<F, R>(self, shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice, &CommandMatch) -> R + 'static,
+                  R: Future<Output = ()> + 'static {
+            self.command(shape, scope, labels, move |state, notice, cm| {
+                let future = action(state, notice, cm);
+                state.spawn(future);
+            })
+        }
+
+        /// Like `pre`, but the action returns a future to handle the
+        /// notice with, or `None` to pass it on
+        #[cfg(feature = "async")]
+        pub fn pre_async<F, R>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice) -> Option<R> + 'static,
+                  R: Future<Output = ()> + 'static {
+            let mut builder = self.armed();
+            builder.handlers.pre_command_handlers.push(Handler { action: spawning(action), priority: 0 });
+            builder.handlers.last = Some(Added::Pre);
+            builder
+        }
+
+        /// Like `post`, but the action returns a future to handle the
+        /// notice with, or `None` to pass it on
+        #[cfg(feature = "async")]
+        pub fn post_async<F, R>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, &Notice) -> Option<R> + 'static,
+                  R: Future<Output = ()> + 'static {
+            let mut builder = self.armed();
+            builder.handlers.post_command_handlers.push(Handler { action: spawning(action), priority: 0 });
+            builder.handlers.last = Some(Added::Post);
+            builder
+        }
+
+        /// Runs an action whenever the bot connects, loses its
+        /// connection, reconnects, or shuts down
+        pub fn on_event<F>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>, Event) + 'static {
+            let mut builder = self.armed();
+            builder.handlers.event_handlers.push(Box::new(action));
+            builder
+        }
+
+        /// Runs an action every `period`, starting one period after
+        /// the bot is built
+        pub fn every<F>(self, period: Duration, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>) + 'static {
+            let mut builder = self.armed();
+            builder.handlers.timers.every(period, Box::new(action));
+            builder
+        }
+
+        /// Runs an action whenever the local time matches a five-field
+        /// cron expression, such as `"30 9 * * 1-5"`. An invalid
+        /// expression makes `build` fail
+        pub fn at<F>(self, cron: &str, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>) + 'static {
+            let mut builder = self.armed();
+            match Cron::parse(cron) {
+                Ok(cron) => builder.handlers.timers.at(cron, Box::new(action)),
+                Err(e) => builder.config.fail(Error::Cron(e)),
+            }
+            builder
+        }
+
+        /// Runs an action when the bot shuts down, before its transport
+        /// is closed, to save state or say goodbye
+        pub fn on_shutdown<F>(self, action: F) -> Builder<E, WithHandlers>
+            where F: Fn(&mut State<E>) + 'static {
+            self.on_event(move |state, event| {
+                if event == Event::Shutdown {
+                    action(state);
+                }
+            })
+        }
+
+        /// Keeps the extra state, location and subscriptions of the bot
+        /// in a JSON file at `path`. They are loaded when the bot is
+        /// built, and saved every minute, on `State::save`, and when
+        /// the bot shuts down. Saved subscriptions replace those given
+        /// to this builder
+        #[cfg(feature = "persist")]
+        pub fn persist_to<P>(self, path: P) -> Builder<E, WithHandlers>
+            where P: AsRef<Path>,
+                  E: Serialize + DeserializeOwned + 'static {
+            let save_path = path.as_ref().to_path_buf();
+            let load_path = save_path.clone();
+
+            let mut builder = self.armed();
+            builder.handlers.persist = Some((
+                Box::new(move |state| persist::save(&save_path, state)),
+                Box::new(move |state| match persist::load(&load_path)? {
+                    Some(saved) => persist::restore(state, saved),
+                    None => Ok(()),
+                }),
+            ));
+
+            builder
+                .every(SAVE_INTERVAL, |state| {
+                    if let Err(e) = state.save() {
+                        eprintln!("failed to save state: {}", e);
+                    }
+                })
+                .on_shutdown(|state| {
+                    if let Err(e) = state.save() {
+                        eprintln!("failed to save state: {}", e);
+                    }
+                })
+        }
+
+        fn armed(self) -> Builder<E, WithHandlers> {
+            Builder {
+                config: self.config,
+                extra: self.extra,
+                handlers: self.handlers,
+                stage: PhantomData,
+            }
+        }
+
+        pub fn build(self) -> Result<Bot<E>> {
+            let config = self.config;
+            let mut handlers = self.handlers;
+
+            if let Some(e) = config.error {
+                return Err(e)
+            }
+
+            if config.help {
+                let help = Command::help(&handlers.commands);
+                handlers.commands.push(help);
+            }
+
+            let zio: Box<dyn Transport> = match config.transport {
+                Some(mut transport) => {
+                    for sub in config.subs {
+                        if !transport.subs().contains(&sub) {
+                            transport.subscribe(sub)?;
+                        }
+                    }
+                    transport
+                },
+                None => Box::new(Zephyr::new(config.subs)?),
+            };
+
+            let mut bot = Bot::new(
+                &config.name,
+                &config.class,
+                &config.instance,
+                config.zsig_func,
+                self.extra,
+                zio,
+                handlers.commands,
+                handlers.pre_command_handlers,
+                handlers.post_command_handlers
+            )?;
+            bot.event_handlers = handlers.event_handlers;
+            bot.layers = handlers.layers;
+            bot.middleware = handlers.middleware;
+            bot.backoff = config.backoff;
+            bot.catch_signals = config.catch_signals;
+            bot.state.referent = config.referent;
+            bot.state.lexicon = config.lexicon;
+            if let Some(backend) = config.store {
+                bot.state.store = Store::from_box(backend);
+            }
+            bot.state.timers = handlers.timers;
+
+            #[cfg(feature = "persist")]
+            {
+                if let Some((save, load)) = handlers.persist {
+                    load(&mut bot.state)?;
+                    bot.state.saver = Some(save);
+                }
+            }
+            Ok(bot)
+        }
+
+        pub fn run(self) -> Result<()> {
+            self.build()?.run()
         }
     }
-}
\ No newline at end of file
+
+    /// Turns an async handler action into a synchronous one which
+    /// spawns its future
+    #[cfg(feature = "async")]
+    fn spawning<E, F, R>(action: F) -> HandlerAction<E>
+        where F: Fn(&mut State<E>, &Notice) -> Option<R> + 'static,
+              R: Future<Output = ()> + 'static {
+        Box::new(move |state, notice| match action(state, notice) {
+            Some(future) => {
+                state.spawn(future);
+                Dispatch::Handled
+            },
+            None => Dispatch::Continue,
+        })
+    }
+}
@@ -1,39 +1,373 @@
 //! Command handling types
 
-use regex::Regex;
+use std::borrow::Cow;
+use std::cmp;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
 use bot;
+use grammar::{GrammarError, Pattern};
 use zephyr;
 
 /// Scope of a command: Local will only respond
 /// to the current Triplet, but Everywhere does not have
 /// this restriction. At(triplet) specifies the command
-/// must be sent to the given triplet
+/// must be sent to the given triplet, and Personal that
+/// it must be sent to the bot alone
+#[derive(Clone)]
 pub enum Scope {
     Local,
     Everywhere,
     At(zephyr::Triplet),
+    Personal,
+}
+
+impl Scope {
+
+    /// Whether a notice to the bot is in this scope
+    pub fn includes<E>(&self, state: &bot::State<E>, notice: &zephyr::Notice) -> bool {
+        match *self {
+            Scope::Local =>
+                state.class == notice.class &&
+                    state.instance == notice.instance,
+            Scope::At(ref triplet) => notice.was_sent_to(triplet),
+            Scope::Personal => notice.is_personal(),
+            Scope::Everywhere => true,
+        }
+    }
 }
 
-/// The "shape" a command is invoked in
-/// patterns should contain named "self" and "cmd"
-/// groups, and numbered groups for arguments
+/// The "shape" a command is invoked in, as one or more templates
+/// in the language of the `grammar` module
+#[derive(Clone)]
 pub struct Shape {
-    patterns: Vec<Regex>,
+    patterns: Vec<Pattern>,
+}
+
+/// How a bot recognises itself in commands, besides its exact name
+#[derive(Clone, Debug, Default)]
+pub struct Referent {
+    pub aliases: Vec<String>,
+    pub ignore_case: bool,
+    /// How many letters may be added, removed or changed. Names are
+    /// allowed a typo per four letters at most, so short ones must
+    /// be exact
+    pub typos: usize,
+}
+
+impl Referent {
+
+    /// Which of `name` and the aliases `said` means, if any. The
+    /// closest wins
+    pub fn matches<'a>(&'a self, name: &'a str, said: &str) -> Option<&'a str> {
+        let fold = |s: &str| if self.ignore_case { s.to_lowercase() } else { s.to_string() };
+        let said = fold(said);
+
+        let mut best: Option<(usize, &str)> = None;
+        for alias in Some(name).into_iter().chain(self.aliases.iter().map(|a| a.as_ref())) {
+            let folded = fold(alias);
+            let distance = if said == folded {
+                0
+            } else if self.typos == 0 {
+                continue
+            } else {
+                edit_distance(&said, &folded)
+            };
+            let allowed = cmp::min(self.typos, folded.chars().count() / 4);
+            let closer = match best {
+                Some((d, _)) => distance < d,
+                None => true,
+            };
+            if distance <= allowed && closer {
+                best = Some((distance, alias));
+            }
+        }
+        best.map(|(_, alias)| alias)
+    }
+}
+
+/// How a bot recognises command labels, besides exactly as written
+#[derive(Clone, Debug, Default)]
+pub struct Lexicon {
+    pub ignore_case: bool,
+    /// Whether to ignore English plurals and conjugations, so that
+    /// "pets" and "petting" both mean "pet"
+    pub stem: bool,
+    /// Which translations to use, besides the synonyms for every locale
+    pub locale: Option<String>,
+    /// Locale, label and synonym
+    synonyms: Vec<(Option<String>, String, String)>,
+}
+
+impl Lexicon {
+
+    /// Makes `words` mean `label` in every locale
+    pub fn synonyms(&mut self, label: &str, words: Vec<&str>) {
+        for word in words {
+            self.synonyms.push((None, label.to_string(), word.to_string()));
+        }
+    }
+
+    /// Makes `words` mean `label` in `locale` only
+    pub fn translate(&mut self, locale: &str, label: &str, words: Vec<&str>) {
+        for word in words {
+            self.synonyms.push((Some(locale.to_string()), label.to_string(), word.to_string()));
+        }
+    }
+
+    /// Which of `labels` `said` means, if any
+    pub fn matches<'a>(&self, labels: &[&'a str], said: &str) -> Option<&'a str> {
+        if let Some(&label) = labels.iter().find(|&&label| label == said) {
+            return Some(label)
+        }
+
+        let said = self.normalize(said);
+        labels.iter().find(|&&label| {
+            self.normalize(label) == said || self.synonyms.iter().any(|(locale, l, word)| {
+                l == label
+                    && (locale.is_none() || *locale == self.locale)
+                    && self.normalize(word) == said
+            })
+        }).cloned()
+    }
+
+    fn normalize(&self, label: &str) -> String {
+        label.split_whitespace()
+            .map(|word| {
+                let word = if self.ignore_case { word.to_lowercase() } else { word.to_string() };
+                if self.stem { stem(&word) } else { word }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Strips common English suffixes, so that different forms of a verb
+/// or noun end up the same: "gives", "giving" and "give" all become
+/// "giv". Short words are left alone
+fn stem(word: &str) -> String {
+    let chars = word.chars().collect::<Vec<_>>();
+    let ends_with = |suffix: &str| word.ends_with(suffix) && chars.len() >= suffix.chars().count() + 3;
+
+    let mut stem = if ends_with("ies") || ends_with("ied") {
+        format!("{}y", &word[..word.len() - 3])
+    } else if ends_with("ing") {
+        word[..word.len() - 3].to_string()
+    } else if ends_with("ed") || ends_with("es") {
+        word[..word.len() - 2].to_string()
+    } else if ends_with("s") && !word.ends_with("ss") {
+        word[..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    };
+
+    // petting, petted: pet
+    let stem_chars = stem.chars().collect::<Vec<_>>();
+    if let [.., a, b] = stem_chars[..] {
+        if a == b && a.is_alphabetic() && !"aeiouylsz".contains(a) {
+            stem.pop();
+        }
+    }
+    // give, giving, gives: giv
+    if stem.chars().count() > 3 && stem.ends_with('e') {
+        stem.pop();
+    }
+    stem
+}
+
+/// Levenshtein distance, in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
 }
 
 /// Represents the result of matching with a Shape
 pub struct CommandMatch<'a> {
+    /// What the bot was called, as written
     pub referent: &'a str,
+    /// The name or alias that matched `referent`
+    pub alias: String,
+    /// The command's label, as written
     pub command: &'a str,
+    /// The command's label that matched `command`
+    pub label: String,
+    /// The arguments as written, without quotes. Escapes in
+    /// double-quoted arguments are left in; see `value`
     pub args: Vec<&'a str>,
+    /// `args` with escapes decoded, only copied where there were any
+    pub(crate) values: Vec<Cow<'a, str>>,
+}
+
+impl<'a> CommandMatch<'a> {
+
+    /// A match of `referent` and `command` exactly as written, with
+    /// arguments which have no escapes
+    pub fn new(referent: &'a str, command: &'a str, args: Vec<&'a str>) -> CommandMatch<'a> {
+        CommandMatch {
+            referent,
+            alias: referent.to_string(),
+            command,
+            label: command.to_string(),
+            values: args.iter().map(|&arg| Cow::Borrowed(arg)).collect(),
+            args,
+        }
+    }
+
+    /// The argument at `index`, with escapes decoded
+    pub fn value(&self, index: usize) -> Option<&str> {
+        self.values.get(index).map(|value| value.as_ref())
+    }
+
+    /// Parses the argument at `index`, as in `cm.arg::<u32>(0)`
+    pub fn arg<T>(&self, index: usize) -> Result<T, ArgError>
+        where T: FromStr, T::Err: Display {
+        parse_arg(&self.strs(), index)
+    }
+
+    /// Parses every argument at once, as a tuple or a type using
+    /// `from_args!`
+    pub fn parse<A: FromArgs>(&self) -> Result<A, ArgError> {
+        A::from_args(&self.strs())
+    }
+
+    fn strs(&self) -> Vec<&str> {
+        self.values.iter().map(|value| value.as_ref()).collect()
+    }
+}
+
+/// An argument which is missing, unexpected or malformed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArgError {
+    /// Counting from 0
+    pub index: usize,
+    pub value: Option<String>,
+    pub reason: String,
+}
+
+impl Display for ArgError {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.value {
+            Some(ref value) => write!(f, "argument {} ({:?}): {}", self.index + 1, value, self.reason),
+            None => write!(f, "argument {}: {}", self.index + 1, self.reason),
+        }
+    }
+}
+
+impl error::Error for ArgError {}
+
+/// Types which can be parsed from a command's arguments
+pub trait FromArgs: Sized {
+    fn from_args(args: &[&str]) -> Result<Self, ArgError>;
+}
+
+/// Parses the argument at `index`
+pub fn parse_arg<T>(args: &[&str], index: usize) -> Result<T, ArgError>
+    where T: FromStr, T::Err: Display {
+    let value = args.get(index).ok_or_else(|| ArgError {
+        index,
+        value: None,
+        reason: "missing".to_string(),
+    })?;
+    value.parse().map_err(|e: T::Err| ArgError {
+        index,
+        value: Some(value.to_string()),
+        reason: e.to_string(),
+    })
+}
+
+/// Fails if there are more than `count` arguments
+pub fn check_count(args: &[&str], count: usize) -> Result<(), ArgError> {
+    match args.get(count) {
+        Some(value) => Err(ArgError {
+            index: count,
+            value: Some(value.to_string()),
+            reason: "unexpected".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+macro_rules! tuple_from_args {
+    ($count:expr; $($t:ident $i:tt),*) => {
+        impl<$($t),*> FromArgs for ($($t,)*)
+            where $($t: FromStr, $t::Err: Display),* {
+            fn from_args(args: &[&str]) -> Result<Self, ArgError> {
+                check_count(args, $count)?;
+                Ok(($(parse_arg::<$t>(args, $i)?,)*))
+            }
+        }
+    }
+}
+
+impl FromArgs for () {
+    fn from_args(args: &[&str]) -> Result<(), ArgError> {
+        check_count(args, 0)
+    }
+}
+
+tuple_from_args!(1; A 0);
+tuple_from_args!(2; A 0, B 1);
+tuple_from_args!(3; A 0, B 1, C 2);
+tuple_from_args!(4; A 0, B 1, C 2, D 3);
+tuple_from_args!(5; A 0, B 1, C 2, D 3, E 4);
+
+/// Any number of arguments of the same type, such as a variadic tail
+impl<T> FromArgs for Vec<T>
+    where T: FromStr, T::Err: Display {
+    fn from_args(args: &[&str]) -> Result<Vec<T>, ArgError> {
+        (0..args.len()).map(|i| parse_arg(args, i)).collect()
+    }
+}
+
+/// Implements `FromArgs` for a struct, parsing each listed field in
+/// order from one argument:
+///
+/// ```
+/// # #[macro_use] extern crate zpet;
+/// # use zpet::command::FromArgs;
+/// struct Give { what: String, count: u32 }
+/// from_args!(Give { what, count });
+/// # fn main() {
+/// let give = Give::from_args(&["treats", "3"]).unwrap();
+/// assert_eq!((give.what.as_str(), give.count), ("treats", 3));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! from_args {
+    ($name:ident { $($field:ident),* $(,)* }) => {
+        impl $crate::command::FromArgs for $name {
+            #[allow(unused_assignments, unused_mut)]
+            fn from_args(args: &[&str]) -> ::std::result::Result<$name, $crate::command::ArgError> {
+                let mut index = 0;
+                $(
+                    let $field = $crate::command::parse_arg(args, index)?;
+                    index += 1;
+                )*
+                $crate::command::check_count(args, index)?;
+                Ok($name { $($field),* })
+            }
+        }
+    }
 }
 
 macro_rules! shape {
-    [$($s:expr,)*] => {{
+    ($templates:expr) => {{
         lazy_static! {
-            static ref PATTERNS: Vec<Regex> = vec![
-                $( Regex::new($s).unwrap() ),*
-            ];
+            static ref PATTERNS: Vec<Pattern> = $templates.iter()
+                .map(|t| Pattern::parse(t).unwrap())
+                .collect();
         };
         Shape {
             patterns: PATTERNS.to_vec(),
@@ -41,32 +375,50 @@ macro_rules! shape {
     }}
 }
 
+/// Templates for each way of calling a method on the bot, as in
+/// `topy.pet`, followed by `call`
+fn methods(call: &str) -> Vec<String> {
+    ["->", ".", "#", "::"].iter()
+        .map(|sep| format!("{{self}}{}{{cmd}}{}", sep, call))
+        .collect()
+}
+
 impl Shape {
 
+    /// A shape matching any of `templates`, tried in order
+    pub fn grammar(templates: &[&str]) -> Result<Shape, GrammarError> {
+        Ok(Shape {
+            patterns: templates.iter().map(|t| Pattern::parse(t)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    /// How to invoke `command` on `referent`, one line per template
+    pub fn usage(&self, referent: &str, command: &str) -> Vec<String> {
+        self.patterns.iter().map(|p| p.usage(referent, command)).collect()
+    }
+
     pub fn try_match<'a>(&self, expected_referent: &str, expected_labels: &Vec<&str>, val: &'a str) -> Option<CommandMatch<'a>> {
+        self.try_match_with(expected_referent, &Referent::default(), &Lexicon::default(), expected_labels, val)
+    }
+
+    /// Like `try_match`, but also accepts whatever `referent` and
+    /// `lexicon` do
+    pub fn try_match_with<'a>(&self, name: &str, referent: &Referent, lexicon: &Lexicon, expected_labels: &[&str], val: &'a str) -> Option<CommandMatch<'a>> {
         for pattern in self.patterns.iter() {
-            if let Some(caps) = pattern.captures(val) {
-                let referent = caps.name("self").unwrap().as_str();
-                if referent != expected_referent {
-                    continue
-                }
-                let command  = caps.name("cmd").unwrap().as_str();
-                if !expected_labels.contains(&command) {
-                    continue
+            if let Some(mut cm) = pattern.try_match(val) {
+                match referent.matches(name, cm.referent) {
+                    Some(alias) => cm.alias = alias.to_string(),
+                    None => continue,
                 }
-                let mut args = vec![];
-                let mut index = 0;
-                loop {
-                    if let Some(m) = caps.name(&format!("{}", index)) {
-                        args.push(m.as_str());
-                    } else {
-                        break
-                    }
-                    index += 1;
+                match lexicon.matches(expected_labels, cm.command) {
+                    Some(label) => cm.label = label.to_string(),
+                    None => continue,
                 }
-                return Some(CommandMatch {
-                    referent, command, args
-                })
+                return Some(cm)
             }
         }
 
@@ -74,118 +426,372 @@ impl Shape {
     }
 
     pub fn order() -> Shape {
-        shape![
-            "^(?P<self>[\\w ]+?) *, *(?P<cmd>[\\w ]+?) *[.!]?$", // topy, sit!
-            "^(?P<cmd>[\\w ]+?) *, *(?P<self>[\\w ]+?) *[.!]?$", // sit, topy!
-        ]
+        shape!([
+            "{self:words}, {cmd:words}[.|!]", // topy, sit!
+            "{cmd:words}, {self:words}[.|!]", // sit, topy!
+        ])
     }
 
     pub fn unary_order() -> Shape {
-        shape![
-            "^(?P<self>[\\w]+) *, *(?P<cmd>[\\w]+) +(?P<0>[\\w]+) *[.!]?$", // topy, get x!
-            "^(?P<cmd>[\\w]+) +(?P<0>[\\w]+) *, *(?P<self>[\\w]+) *[.!]?$", // get x, topy!
-        ]
+        shape!([
+            "{self}, {cmd} {arg}[.|!]", // topy, get x!
+            "{cmd} {arg}, {self}[.|!]", // get x, topy!
+        ])
     }
 
     pub fn invoke() -> Shape {
-        shape![
-            "^(?P<self>[\\w]+) *\\((?P<cmd>[ \\w]+)\\)$",     // topy(pet)
-            "^(?P<cmd>[ \\w]+)s +(?P<self>[\\w]+)$",          // pets topy
-            "^(?P<self>[\\w]+) *-> *\\{(?P<cmd>[ \\w]+)\\}$", // topy->{pet}
-            "^(?P<self>[\\w]+) *(?:->|\\.|#|::) *(?P<cmd>[\\w]+)(?:\\(\\))?$", // topy.pet, topy.pet()
-        ]
+        shape!([
+            "{self}({cmd:words})",       // topy(pet)
+            "{cmd:words}s {self}",       // pets topy
+            "{self}->\\{{cmd:words}\\}", // topy->{pet}
+        ].iter()
+            .map(|t| t.to_string())
+            .chain(methods("[()]")) // topy.pet, topy.pet()
+            .collect::<Vec<_>>())
     }
 
     pub fn unary_invoke() -> Shape {
-        shape![
-            "^(?P<self>[\\w]+) *(?:->|\\.|#|::) *(?P<cmd>[\\w]+)(?:\\( *(?P<0>[\\w-.]+) *\\))?$",
-            "^(?P<self>[\\w]+) *(?:->|\\.|#|::) *(?P<cmd>[\\w]+)(?:\\( *'(?P<0>[^']+)' *\\))?$",
-        ]
+        shape!(methods("[({arg:token})]").into_iter()
+            .chain(methods("[({arg:quoted})]"))
+            .collect::<Vec<_>>())
     }
 
     pub fn binary_invoke() -> Shape {
-        shape![
-            "^(?P<self>[\\w]+) *(?:->|\\.|#|::) *(?P<cmd>[\\w]+)(?:\\( *(?P<0>[\\w-.]+) *, *(?P<1>[\\w-.]+) *\\))?$",
-            "^(?P<self>[\\w]+) *(?:->|\\.|#|::) *(?P<cmd>[\\w]+)(?:\\( *'(?P<0>[^']+)' *, *'(?P<1>[^']+)' *\\))?$",
-        ]
+        shape!(methods("[({a:token}, {b:token})]").into_iter()
+            .chain(methods("[({a:quoted}, {b:quoted})]"))
+            .collect::<Vec<_>>())
+    }
+
+    /// Any number of arguments, which may be quoted, as in
+    /// `topy.remember('a', "b", c)`
+    pub fn nary_invoke() -> Shape {
+        shape!(methods("[({args:value...})]"))
+    }
+
+    /// An order with the rest of the message as one argument, as in
+    /// `topy, say anything at all`
+    pub fn rest_order() -> Shape {
+        shape!([
+            "{self}, {cmd} {text:rest}", // topy, say hi!
+        ])
+    }
+
+    /// How to ask for help, as in `topy, help!` or `topy.help(sit)`
+    pub fn help() -> Shape {
+        shape!([
+            "{self}, {cmd} [{topic:words}][.|!|?]", // topy, help sit!
+            "{self}.{cmd}[([{topic:words}])]",      // topy.help(sit)
+        ])
     }
 
     pub fn do_with() -> Shape {
-        shape![
-            "(?:^[\\w]+ +)?(?P<cmd>[\\w]+) +(?P<self>[\\w]+) +(?P<0>[ \\w]+)[.!]?$",
-        ]
+        shape!([
+            "[{_}] {cmd} {self} {how:words}[.|!]", // alice pets topy gently
+        ])
+    }
+}
+
+/// What a command or handler did with a notice, and so whether
+/// anything else gets to see it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Dealt with, so nothing else sees the notice
+    Handled,
+    /// Not dealt with, or dealt with but others may see it too
+    Continue,
+    /// Not dealt with, and nothing else may see it either
+    Stop,
+}
+
+impl Dispatch {
+
+    /// Whether nothing else should see the notice
+    pub fn is_final(self) -> bool {
+        self != Dispatch::Continue
+    }
+}
+
+/// Actions which don't return anything handled the notice
+impl From<()> for Dispatch {
+    fn from(_: ()) -> Dispatch {
+        Dispatch::Handled
+    }
+}
+
+/// `true` if the notice was handled, as handlers used to return
+impl From<bool> for Dispatch {
+    fn from(handled: bool) -> Dispatch {
+        if handled { Dispatch::Handled } else { Dispatch::Continue }
     }
 }
 
+/// Code run around every handler, and every command which matches a
+/// notice, for logging, rate limiting, checking permissions and the
+/// like. See `Builder::middleware`
+pub trait Middleware<E> {
+
+    /// Runs before a command or handler. Returning `Some(outcome)`
+    /// skips it, as if it had returned `outcome`
+    fn before(&self, _state: &mut bot::State<E>, _notice: &zephyr::Notice) -> Option<Dispatch> {
+        None
+    }
+
+    /// Runs after a command or handler, or after a `before` skipped
+    /// it, with what it returned. Returns the outcome to go on with
+    fn after(&self, _state: &mut bot::State<E>, _notice: &zephyr::Notice, outcome: Dispatch) -> Dispatch {
+        outcome
+    }
+}
+
+/// Runs `action` inside `middleware`, the first outermost
+fn wrapped<E, F>(middleware: &[Box<dyn Middleware<E>>], state: &mut bot::State<E>, notice: &zephyr::Notice, action: F) -> Dispatch
+    where F: FnOnce(&mut bot::State<E>) -> Dispatch {
+    let mut entered = 0;
+    let mut outcome = None;
+    for m in middleware.iter() {
+        entered += 1;
+        outcome = m.before(state, notice);
+        if outcome.is_some() {
+            break
+        }
+    }
+
+    let outcome = match outcome {
+        Some(outcome) => outcome,
+        None => action(state),
+    };
+    middleware[..entered].iter().rev().fold(outcome, |outcome, m| m.after(state, notice, outcome))
+}
+
+/// The action run when a command matches
+pub type CommandAction<E> = Box<dyn Fn(&mut bot::State<E>, &zephyr::Notice, &CommandMatch) -> Dispatch>;
+
+/// The action run by a handler
+pub type HandlerAction<E> = Box<dyn Fn(&mut bot::State<E>, &zephyr::Notice) -> Dispatch>;
+
 /// Represents a command, which may be
 /// executed may be executed against a notice
 pub struct Command<E> {
     shape: Shape,
     scope: Scope,
     labels: Vec<String>,
-    action: Box<Fn(&mut bot::State<E>, &zephyr::Notice, &CommandMatch) -> ()>
+    action: CommandAction<E>,
+    priority: i32,
+    description: Option<String>,
+    example: Option<String>,
 }
 
 impl<E> Command<E> {
 
-    pub fn new<F>(shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Command<E>
-        where F: Fn(&mut bot::State<E>, &zephyr::Notice, &CommandMatch) -> () + 'static {
+    /// The action may return nothing, meaning `Dispatch::Handled`, or
+    /// a `Dispatch`
+    pub fn new<F, R>(shape: Shape, scope: Scope, labels: Vec<&str>, action: F) -> Command<E>
+        where F: Fn(&mut bot::State<E>, &zephyr::Notice, &CommandMatch) -> R + 'static,
+              R: Into<Dispatch> {
 
         Command {
             shape,
             scope,
             labels: labels.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
-            action: Box::new(action)
+            action: Box::new(move |state, notice, cm| action(state, notice, cm).into()),
+            priority: 0,
+            description: None,
+            example: None,
         }
     }
 
+    /// Commands with a higher priority are tried first. Those with the
+    /// same priority are tried in the order they were added
+    pub fn with_priority(mut self, priority: i32) -> Command<E> {
+        self.priority = priority;
+        self
+    }
 
-    pub fn try_exec(&self, state: &mut bot::State<E>, notice: &zephyr::Notice) -> bool {
-        if let Some(cm) = self.shape.try_match(
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Sets what the command does, for `help`
+    pub fn describe(mut self, description: &str) -> Command<E> {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Sets an example of invoking the command, for `help`
+    pub fn with_example(mut self, example: &str) -> Command<E> {
+        self.example = Some(example.to_string());
+        self
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.shape
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(|x| x.as_ref())
+    }
+
+    pub fn example(&self) -> Option<&str> {
+        self.example.as_ref().map(|x| x.as_ref())
+    }
+
+    /// A command answering `help` with the commands available where it
+    /// is asked, and `help <label>` with the details of one
+    pub fn help(commands: &[Command<E>]) -> Command<E> {
+        let entries = commands.iter().map(|c| Help {
+            shape: c.shape.clone(),
+            scope: c.scope.clone(),
+            labels: c.labels.clone(),
+            description: c.description.clone(),
+            example: c.example.clone(),
+        }).collect::<Vec<_>>();
+
+        Command::new(Shape::help(), Scope::Everywhere, vec!["help"], move |state, notice, cm| {
+            let visible = entries.iter()
+                .filter(|entry| entry.scope.includes(state, notice))
+                .collect::<Vec<_>>();
+
+            let body = match cm.value(0) {
+                Some(topic) => {
+                    let found = visible.iter().find(|entry| {
+                        let labels = entry.labels.iter().map(|x| x.as_ref()).collect::<Vec<_>>();
+                        state.lexicon.matches(&labels, topic).is_some()
+                    });
+                    match found {
+                        Some(entry) => entry.details(&state.name),
+                        None => format!("I don't know a command called {:?}", topic),
+                    }
+                },
+                None if visible.is_empty() => "I don't know any commands here".to_string(),
+                None => {
+                    let mut lines = visible.iter().map(|entry| entry.summary(&state.name)).collect::<Vec<_>>();
+                    lines.push(format!("Say \"{}, help <command>\" for details", state.name));
+                    lines.join("\n")
+                },
+            };
+            if let Err(e) = state.reply_to(notice, &body) {
+                eprintln!("failed to send help: {}", e);
+            }
+        }).describe("Lists commands, or explains one")
+    }
+
+
+    pub fn try_exec(&self, state: &mut bot::State<E>, notice: &zephyr::Notice) -> Dispatch {
+        self.try_exec_with(&[], state, notice)
+    }
+
+    /// Like `try_exec`, but runs the action inside `middleware`, if it
+    /// matches
+    pub fn try_exec_with(&self, middleware: &[Box<dyn Middleware<E>>], state: &mut bot::State<E>, notice: &zephyr::Notice) -> Dispatch {
+        if let Some(cm) = self.shape.try_match_with(
             &state.name,
+            &state.referent,
+            &state.lexicon,
             &self.labels.iter().map(|x| x.as_ref()).collect::<Vec<_>>(),
-            &notice.body.join("\n").trim()) {
+            notice.body.join("\n").trim()) {
 
             if !state.subs().iter().any(|t| notice.was_sent_to(t)) {
-                return false
+                return Dispatch::Continue
             }
 
-            match self.scope {
-                Scope::Local => if !(
-                    state.class == notice.class &&
-                        state.instance == notice.instance) {return false},
-                Scope::At(ref triplet) => if !notice.was_sent_to(triplet) {return false},
-                _ => {},
+            if !self.scope.includes(state, notice) {
+                return Dispatch::Continue
             }
 
-            (self.action)(state, notice, &cm);
-            true
+            wrapped(middleware, state, notice, |state| (self.action)(state, notice, &cm))
         } else {
-            false
+            Dispatch::Continue
+        }
+    }
+}
+
+/// What `help` knows about a command
+struct Help {
+    shape: Shape,
+    scope: Scope,
+    labels: Vec<String>,
+    description: Option<String>,
+    example: Option<String>,
+}
+
+impl Help {
+
+    /// One line: the label, how to use it, and what it does
+    fn summary(&self, name: &str) -> String {
+        let label = self.labels.first().map(|x| x.as_ref()).unwrap_or("");
+        let usage = self.shape.usage(name, label).into_iter().next().unwrap_or_default();
+        match self.description {
+            Some(ref description) => format!("{}: {} - {}", label, usage, description),
+            None => format!("{}: {}", label, usage),
         }
     }
+
+    fn details(&self, name: &str) -> String {
+        let label = self.labels.first().map(|x| x.as_ref()).unwrap_or("");
+        let mut lines = vec![];
+        match self.description {
+            Some(ref description) => lines.push(format!("{}: {}", label, description)),
+            None => lines.push(label.to_string()),
+        }
+        if self.labels.len() > 1 {
+            lines.push(format!("also: {}", self.labels[1..].join(", ")));
+        }
+        for (i, usage) in self.shape.usage(name, label).into_iter().enumerate() {
+            lines.push(format!("{}{}", if i == 0 { "usage: " } else { "       " }, usage));
+        }
+        if let Some(ref example) = self.example {
+            lines.push(format!("e.g. {}", example));
+        }
+        lines.join("\n")
+    }
 }
 
 /// Represents a handler which does not need to extract
 /// a command from a string
 pub struct Handler<E> {
-    pub action: Box<Fn(&mut bot::State<E>, &zephyr::Notice) -> bool>
+    pub action: HandlerAction<E>,
+    /// As for `Command::with_priority`
+    pub priority: i32,
 }
 
 impl<E> Handler<E> {
 
-    pub fn new<F>(action: F) -> Handler<E>
-        where F: Fn(&mut bot::State<E>, &zephyr::Notice) -> bool + 'static {
-        Handler{ action: Box::new(action) }
+    /// The action may return whether it handled the notice, or a
+    /// `Dispatch`
+    pub fn new<F, R>(action: F) -> Handler<E>
+        where F: Fn(&mut bot::State<E>, &zephyr::Notice) -> R + 'static,
+              R: Into<Dispatch> {
+        Handler {
+            action: Box::new(move |state, notice| action(state, notice).into()),
+            priority: 0,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Handler<E> {
+        self.priority = priority;
+        self
+    }
+
+    pub fn try_exec(&self, state: &mut bot::State<E>, notice: &zephyr::Notice) -> Dispatch {
+        self.try_exec_with(&[], state, notice)
     }
 
-    pub fn try_exec(&self, state: &mut bot::State<E>, notice: &zephyr::Notice) -> bool {
+    /// Like `try_exec`, but runs the action inside `middleware`
+    pub fn try_exec_with(&self, middleware: &[Box<dyn Middleware<E>>], state: &mut bot::State<E>, notice: &zephyr::Notice) -> Dispatch {
 
         if !state.subs().iter().any(|t| notice.was_sent_to(t)) {
-            return false
+            return Dispatch::Continue
         }
 
-        (self.action)(state, notice)
+        wrapped(middleware, state, notice, |state| (self.action)(state, notice))
     }
 }
 
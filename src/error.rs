@@ -0,0 +1,110 @@
+//! Error type shared by the whole crate
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::result;
+
+use grammar::GrammarError;
+use native::DecodeError;
+use schedule::CronError;
+use zephyr::FrameError;
+
+/// Anything that can go wrong while talking to Zephyr
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error talking to zwgc, zwrite, or the hostmanager
+    Io(io::Error),
+    /// zwgc printed something which is not a notice
+    Frame(FrameError),
+    /// A datagram which is not a valid ZephyrGram
+    Decode(DecodeError),
+    /// zwrite, zctl or the server refused a request
+    Rejected(String),
+    /// A timer was given a malformed cron expression
+    Cron(CronError),
+    /// A command shape was given a malformed template
+    Grammar(GrammarError),
+    /// A bot was built with an invalid setting
+    Config(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+
+    /// Whether this error means the connection was lost, and should
+    /// be re-established with `Transport::reconnect`
+    pub fn is_disconnect(&self) -> bool {
+        match *self {
+            Error::Io(ref e) => matches!(e.kind(),
+                ErrorKind::UnexpectedEof |
+                ErrorKind::NotConnected |
+                ErrorKind::BrokenPipe |
+                ErrorKind::ConnectionRefused |
+                ErrorKind::ConnectionReset |
+                ErrorKind::ConnectionAborted),
+            _ => false,
+        }
+    }
+}
+
+impl Display for Error {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Frame(ref e) => write!(f, "{}", e),
+            Error::Decode(ref e) => write!(f, "{}", e),
+            Error::Rejected(ref why) => write!(f, "{}", why),
+            Error::Cron(ref e) => write!(f, "{}", e),
+            Error::Grammar(ref e) => write!(f, "{}", e),
+            Error::Config(ref why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl error::Error for Error {
+
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Frame(ref e) => Some(e),
+            Error::Decode(ref e) => Some(e),
+            Error::Rejected(_) => None,
+            Error::Cron(ref e) => Some(e),
+            Error::Grammar(ref e) => Some(e),
+            Error::Config(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<FrameError> for Error {
+    fn from(e: FrameError) -> Error {
+        Error::Frame(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::Decode(e)
+    }
+}
+
+impl From<CronError> for Error {
+    fn from(e: CronError) -> Error {
+        Error::Cron(e)
+    }
+}
+
+impl From<GrammarError> for Error {
+    fn from(e: GrammarError) -> Error {
+        Error::Grammar(e)
+    }
+}
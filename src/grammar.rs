@@ -0,0 +1,493 @@
+//! A small language for describing how commands are invoked, compiled
+//! into the patterns behind a `Shape`
+//!
+//! A template is matched against the whole message. It is made of:
+//!
+//! - `{self}` and `{cmd}`, exactly once each: the bot's name and the
+//!   command's label. Both are a single word unless written
+//!   `{self:words}` or `{cmd:words}`
+//! - `{name:kind}` for an argument, where `kind` is one of `word`,
+//!   `words`, `token`, `int`, `number`, `quoted`, `value` or `rest`,
+//!   and defaults to `word`. `name` only documents the argument; arguments
+//!   are numbered in the order they appear. `{_:kind}` matches without
+//!   becoming an argument
+//! - `{name:kind...}` for one or more arguments separated by commas or
+//!   spaces, which must come last
+//! - `[...]` for an optional part, and `[a|b]` for a choice of optional
+//!   parts
+//! - anything else is matched as is. Spaces match one or more spaces,
+//!   and punctuation may have spaces around it. `\` escapes any of
+//!   `{}[]|\`
+//!
+//! For example, `"{self}, {cmd} {thing}[.|!]"` matches "topy, get ball!",
+//! and `"{self}.{cmd}({who:quoted}, {times:int})"` matches
+//! "topy.pet('alice', 3)".
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::str::Chars;
+
+use regex::{self, Regex};
+
+use command::CommandMatch;
+
+/// An invalid template
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GrammarError {
+    pub template: String,
+    pub reason: String,
+}
+
+impl Display for GrammarError {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid command template {:?}: {}", self.template, self.reason)
+    }
+}
+
+impl error::Error for GrammarError {}
+
+/// What an argument may contain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgKind {
+    /// Letters, digits and underscores
+    Word,
+    /// Words separated by spaces
+    Words,
+    /// Like a word, but may also contain `.` and `-`
+    Token,
+    /// An integer, optionally signed
+    Int,
+    /// An integer or decimal, optionally signed
+    Number,
+    /// Anything between single quotes, or between double quotes with
+    /// `\` escapes, without the quotes
+    Quoted,
+    /// A token or a quoted string
+    Value,
+    /// Anything at all, up to the end of the message or the next part
+    /// of the template
+    Rest,
+}
+
+impl ArgKind {
+
+    fn parse(kind: &str) -> Option<ArgKind> {
+        match kind {
+            "word" => Some(ArgKind::Word),
+            "words" => Some(ArgKind::Words),
+            "token" => Some(ArgKind::Token),
+            "int" => Some(ArgKind::Int),
+            "number" => Some(ArgKind::Number),
+            "quoted" => Some(ArgKind::Quoted),
+            "value" => Some(ArgKind::Value),
+            "rest" => Some(ArgKind::Rest),
+            _ => None,
+        }
+    }
+
+    /// A regex matching one argument, quotes and all
+    fn item(self) -> &'static str {
+        match self {
+            ArgKind::Word => "\\w+",
+            ArgKind::Words => "\\w+(?: +\\w+)*?",
+            ArgKind::Token => "[\\w.-]+",
+            ArgKind::Int => "[+-]?\\d+",
+            ArgKind::Number => "[+-]?\\d+(?:\\.\\d+)?",
+            ArgKind::Quoted => "(?:'[^']*'|\"(?:[^\"\\\\]|\\\\.)*\")",
+            ArgKind::Value => "(?:'[^']*'|\"(?:[^\"\\\\]|\\\\.)*\"|[\\w.-]+)",
+            ArgKind::Rest => "(?s:.+?)",
+        }
+    }
+
+    /// A regex matching one argument, capturing it as `group`
+    fn capture(self, group: &str) -> String {
+        format!("(?P<{}>{})", group, self.item())
+    }
+
+    /// Whether several of these can be told apart
+    fn repeatable(self) -> bool {
+        !matches!(self, ArgKind::Words | ArgKind::Rest)
+    }
+}
+
+/// An argument in a template
+#[derive(Clone, Debug)]
+pub struct Arg {
+    pub name: String,
+    pub kind: ArgKind,
+    /// Whether this is a tail of one or more arguments
+    pub variadic: bool,
+    /// Whether this is inside an optional part
+    pub optional: bool,
+    /// Picks single arguments out of a variadic tail
+    items: Option<Regex>,
+}
+
+/// A compiled template
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    template: String,
+    regex: Regex,
+    args: Vec<Arg>,
+}
+
+impl Pattern {
+
+    pub fn parse(template: &str) -> Result<Pattern, GrammarError> {
+        let error = |reason: String| GrammarError { template: template.to_string(), reason };
+
+        let mut parser = Parser {
+            chars: template.trim().chars(),
+            args: vec![],
+            referents: 0,
+            commands: 0,
+        };
+        let mut alternatives = parser.sequence(0).map_err(&error)?;
+        let tokens = alternatives.remove(0);
+
+        match (parser.referents, parser.commands) {
+            (1, 1) => {},
+            (1, _) => return Err(error("expected exactly one {cmd}".to_string())),
+            _ => return Err(error("expected exactly one {self}".to_string())),
+        }
+        if let Some(i) = parser.args.iter().position(|arg| arg.variadic) {
+            if i + 1 != parser.args.len() {
+                return Err(error(format!("{{{}...}} must be the last argument", parser.args[i].name)))
+            }
+        }
+
+        let mut source = "^ *".to_string();
+        emit(&tokens.iter().collect::<Vec<_>>(), &parser.args, &mut source);
+        source.push_str(" *$");
+        let regex = Regex::new(&source).map_err(|e| error(e.to_string()))?;
+
+        Ok(Pattern { template: template.to_string(), regex, args: parser.args })
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// The arguments, in order
+    pub fn args(&self) -> &[Arg] {
+        &self.args
+    }
+
+    /// The template as a human would read it, invoking `command` on
+    /// `referent`, with arguments as `<name>`
+    pub fn usage(&self, referent: &str, command: &str) -> String {
+        let mut usage = String::new();
+        let mut chars = self.template.trim().chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => usage.extend(chars.next()),
+                '{' => {
+                    let spec = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                    let (spec, variadic) = match spec.trim().strip_suffix("...") {
+                        Some(spec) => (spec.to_string(), "..."),
+                        None => (spec.trim().to_string(), ""),
+                    };
+                    let mut parts = spec.splitn(2, ':').map(str::trim);
+                    let name = parts.next().unwrap_or("");
+                    match name {
+                        "self" => usage.push_str(referent),
+                        "cmd" => usage.push_str(command),
+                        "_" => usage.push_str(&format!("<{}>{}", parts.next().unwrap_or("word"), variadic)),
+                        _ => usage.push_str(&format!("<{}>{}", name, variadic)),
+                    }
+                },
+                c => usage.push(c),
+            }
+        }
+        usage
+    }
+
+    /// Matches a whole message, whatever its referent and command,
+    /// leaving `alias` and `label` empty. Optional arguments which
+    /// are missing are left out of `args`, and variadic tails are
+    /// split into one argument each
+    pub fn try_match<'a>(&self, val: &'a str) -> Option<CommandMatch<'a>> {
+        let caps = self.regex.captures(val)?;
+
+        let mut items = vec![];
+        for (i, arg) in self.args.iter().enumerate() {
+            let m = match caps.name(&group(i)) {
+                Some(m) => m.as_str(),
+                None => continue,
+            };
+            match arg.items {
+                Some(ref regex) => for item in regex.find_iter(m) {
+                    items.push(unquote(arg.kind, item.as_str()));
+                },
+                None => items.push(unquote(arg.kind, m)),
+            }
+        }
+
+        Some(CommandMatch {
+            referent: caps.name("self").unwrap().as_str(),
+            alias: String::new(),
+            command: caps.name("cmd").unwrap().as_str(),
+            label: String::new(),
+            args: items.iter().map(|&(arg, _)| arg).collect(),
+            values: items.into_iter().map(|(_, value)| value).collect(),
+        })
+    }
+}
+
+fn group(index: usize) -> String {
+    format!("arg{}", index)
+}
+
+/// A matched argument without its quotes, and its value with any
+/// escapes decoded
+fn unquote(kind: ArgKind, item: &str) -> (&str, Cow<'_, str>) {
+    let quoted = matches!(kind, ArgKind::Quoted | ArgKind::Value) &&
+        (item.starts_with('\'') || item.starts_with('"'));
+    if !quoted {
+        return (item, Cow::Borrowed(item))
+    }
+    let inner = &item[1..item.len() - 1];
+    let value = match item.chars().next() {
+        Some('"') if inner.contains('\\') => {
+            let mut value = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some(c) => value.push(c),
+                        None => {},
+                    },
+                    c => value.push(c),
+                }
+            }
+            Cow::Owned(value)
+        },
+        _ => Cow::Borrowed(inner),
+    };
+    (inner, value)
+}
+
+/// A piece of a template
+enum Token {
+    /// Letters, digits and underscores, matched as is
+    Text(String),
+    /// Anything else, which may have spaces around it
+    Punct(String),
+    Space,
+    Referent(ArgKind),
+    Command(ArgKind),
+    /// An argument, by index
+    Arg(usize),
+    /// A slot which is matched but not captured
+    Ignored(ArgKind, bool),
+    /// A choice of optional parts
+    Optional(Vec<Vec<Token>>),
+}
+
+struct Parser<'a> {
+    chars: Chars<'a>,
+    args: Vec<Arg>,
+    referents: usize,
+    commands: usize,
+}
+
+impl<'a> Parser<'a> {
+
+    /// Reads `|`-separated alternatives, up to the `]` closing `depth`
+    /// levels of brackets, or the end at depth 0
+    fn sequence(&mut self, depth: usize) -> Result<Vec<Vec<Token>>, String> {
+        let mut alternatives = vec![vec![]];
+        loop {
+            let c = match self.chars.next() {
+                Some(c) => c,
+                None if depth > 0 => return Err("unclosed [".to_string()),
+                None => return Ok(alternatives),
+            };
+            let tokens = alternatives.last_mut().unwrap();
+            match c {
+                '[' => {
+                    let optional = self.sequence(depth + 1)?;
+                    tokens.push(Token::Optional(optional));
+                },
+                ']' if depth == 0 => return Err("unmatched ]".to_string()),
+                ']' => return Ok(alternatives),
+                '|' if depth == 0 => return Err("| is only allowed inside [...]".to_string()),
+                '|' => alternatives.push(vec![]),
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match self.chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err("unclosed {".to_string()),
+                        }
+                    }
+                    let token = self.slot(&spec, depth > 0)?;
+                    tokens.push(token);
+                },
+                '}' => return Err("unmatched }".to_string()),
+                '\\' => match self.chars.next() {
+                    Some(c) => push_char(tokens, c),
+                    None => return Err("nothing to escape after \\".to_string()),
+                },
+                c if c.is_whitespace() => {
+                    if !matches!(tokens.last(), Some(&Token::Space)) {
+                        tokens.push(Token::Space);
+                    }
+                },
+                c => push_char(tokens, c),
+            }
+        }
+    }
+
+    fn slot(&mut self, spec: &str, optional: bool) -> Result<Token, String> {
+        let (spec, variadic) = match spec.trim().strip_suffix("...") {
+            Some(spec) => (spec, true),
+            None => (spec.trim(), false),
+        };
+        let (name, kind) = match spec.find(':') {
+            Some(i) => (spec[..i].trim(), spec[i + 1..].trim()),
+            None => (spec, "word"),
+        };
+        let kind = ArgKind::parse(kind).ok_or_else(|| format!("unknown kind {:?} for {{{}}}", kind, name))?;
+
+        if variadic && !kind.repeatable() {
+            return Err(format!("{{{}}} can't be repeated", spec))
+        }
+
+        match name {
+            "self" | "cmd" => {
+                if optional {
+                    return Err(format!("{{{}}} can't be optional", name))
+                }
+                if variadic || !matches!(kind, ArgKind::Word | ArgKind::Words) {
+                    return Err(format!("{{{}}} must be a word or words", name))
+                }
+                if name == "self" {
+                    self.referents += 1;
+                    Ok(Token::Referent(kind))
+                } else {
+                    self.commands += 1;
+                    Ok(Token::Command(kind))
+                }
+            },
+            "_" => Ok(Token::Ignored(kind, variadic)),
+            _ => {
+                if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(format!("invalid argument name {:?}", name))
+                }
+                let items = if variadic {
+                    Some(Regex::new(kind.item()).unwrap())
+                } else {
+                    None
+                };
+                self.args.push(Arg { name: name.to_string(), kind, variadic, optional, items });
+                Ok(Token::Arg(self.args.len() - 1))
+            },
+        }
+    }
+}
+
+fn push_char(tokens: &mut Vec<Token>, c: char) {
+    let word = c.is_alphanumeric() || c == '_';
+    match tokens.last_mut() {
+        Some(&mut Token::Text(ref mut text)) if word => return text.push(c),
+        Some(&mut Token::Punct(ref mut text)) if !word => return text.push(c),
+        _ => {},
+    }
+    tokens.push(if word { Token::Text(c.to_string()) } else { Token::Punct(c.to_string()) });
+}
+
+/// A regex matching one or more of `kind`, separated by commas or spaces
+fn repeated(kind: ArgKind) -> String {
+    format!("{0}(?:(?: *, *| +){0})*", kind.item())
+}
+
+fn is_punct(token: Option<&&Token>) -> bool {
+    matches!(token, Some(&&Token::Punct(_)))
+}
+
+fn is_space(token: Option<&&Token>) -> bool {
+    matches!(token, Some(&&Token::Space))
+}
+
+fn is_optional(token: Option<&&Token>) -> bool {
+    matches!(token, Some(&&Token::Optional(_)))
+}
+
+/// Whether the optional part at `i` takes the space before it and the
+/// space after it, which are then only needed when the part is there.
+/// At most one of them is taken, so that the parts around it are still
+/// spaced when it is missing
+fn padding(tokens: &[&Token], i: usize) -> (bool, bool) {
+    let before = |n: usize| if i >= n { tokens.get(i - n) } else { None };
+    let after = |n: usize| tokens.get(i + n);
+
+    let spaced = |t: Option<&&Token>| t.is_none() || is_space(t) || is_punct(t) || is_optional(t);
+    let lead = is_space(before(1)) && !is_punct(before(2)) && spaced(after(1));
+    let trail = !lead && is_space(after(1)) && !is_punct(after(2)) && !is_optional(after(2));
+    (lead, trail)
+}
+
+fn emit(tokens: &[&Token], args: &[Arg], out: &mut String) {
+    for (i, token) in tokens.iter().enumerate() {
+        let before = |n: usize| if i >= n { tokens.get(i - n) } else { None };
+        let after = |n: usize| tokens.get(i + n);
+
+        match **token {
+            Token::Text(ref text) => out.push_str(&regex::escape(text)),
+            Token::Punct(ref text) => {
+                out.push_str(" *");
+                out.push_str(&regex::escape(text));
+                out.push_str(" *");
+            },
+            Token::Space => {
+                // punctuation allows its own spaces
+                let taken = (is_optional(before(1)) && padding(tokens, i - 1).1)
+                    || (is_optional(after(1)) && padding(tokens, i + 1).0);
+                if !is_punct(before(1)) && !is_punct(after(1)) && !taken {
+                    out.push_str(" +");
+                }
+            },
+            Token::Referent(kind) => out.push_str(&kind.capture("self")),
+            Token::Command(kind) => out.push_str(&kind.capture("cmd")),
+            Token::Arg(index) => {
+                let arg = &args[index];
+                if arg.variadic {
+                    out.push_str(&format!("(?P<{}>{})", group(index), repeated(arg.kind)));
+                } else {
+                    out.push_str(&arg.kind.capture(&group(index)));
+                }
+            },
+            Token::Ignored(kind, variadic) => {
+                let item = if variadic { repeated(kind) } else { kind.item().to_string() };
+                out.push_str(&format!("(?:{})", item));
+            },
+            Token::Optional(ref alternatives) => {
+                let space = Token::Space;
+                let (lead, trail) = padding(tokens, i);
+
+                out.push_str("(?:");
+                for (j, alternative) in alternatives.iter().enumerate() {
+                    if j > 0 {
+                        out.push('|');
+                    }
+                    let mut padded = vec![];
+                    if lead {
+                        padded.push(&space);
+                    }
+                    padded.extend(alternative.iter());
+                    if trail {
+                        padded.push(&space);
+                    }
+                    emit(&padded, args, out);
+                }
+                out.push_str(")?");
+            },
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Handles for talking to a running bot from other threads
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use error::Result;
+use zephyr::{Notice, Triplet};
+
+/// Something another thread has asked the bot to do
+pub enum Request {
+    /// Handle a notice as if it had been received
+    Inject(Notice),
+    /// Send a notice through the bot's transport
+    Send(Notice),
+    /// Send a message from the bot, with its usual zsig
+    Reply(Triplet, String),
+    /// Stop the bot, as `State::shutdown`
+    Shutdown,
+}
+
+/// A cloneable, thread-safe handle to a bot, from `Bot::handle`.
+/// Requests are carried out by the bot's event loop, in order
+#[derive(Clone)]
+pub struct Handle {
+    sender: Sender<Request>,
+    waker: Arc<UnixStream>,
+}
+
+impl Handle {
+
+    /// Has the bot handle a notice as if it had been received
+    pub fn inject(&self, notice: Notice) -> Result<()> {
+        self.request(Request::Inject(notice))
+    }
+
+    /// Has the bot send a notice as is
+    pub fn send(&self, notice: Notice) -> Result<()> {
+        self.request(Request::Send(notice))
+    }
+
+    /// Has the bot send a message to a triplet, as `State::reply_at`
+    pub fn reply_at(&self, triplet: &Triplet, body: &str) -> Result<()> {
+        self.request(Request::Reply(triplet.clone(), body.to_string()))
+    }
+
+    /// Has the bot reply to a notice, as `State::reply_to`
+    pub fn reply_to(&self, notice: &Notice, body: &str) -> Result<()> {
+        self.reply_at(&notice.reply_triplet(), body)
+    }
+
+    /// Has the bot shut down, as `State::shutdown`
+    pub fn shutdown(&self) -> Result<()> {
+        self.request(Request::Shutdown)
+    }
+
+    /// Wakes the bot's event loop, without asking it to do anything
+    pub fn wake(&self) -> Result<()> {
+        // a full socket already has a wakeup pending
+        match (&*self.waker).write(&[0]) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn request(&self, request: Request) -> Result<()> {
+        self.sender.send(request)
+            .map_err(|_| io::Error::new(ErrorKind::NotConnected, "the bot has stopped"))?;
+        self.wake()
+    }
+}
+
+/// The receiving end of a bot's handles
+pub struct Mailbox {
+    receiver: Receiver<Request>,
+    wakeups: UnixStream,
+    handle: Handle,
+}
+
+impl Mailbox {
+
+    pub fn new() -> Result<Mailbox> {
+        let (wakeups, waker) = UnixStream::pair()?;
+        wakeups.set_nonblocking(true)?;
+        waker.set_nonblocking(true)?;
+
+        let (sender, receiver) = mpsc::channel();
+        Ok(Mailbox {
+            receiver,
+            wakeups,
+            handle: Handle { sender, waker: Arc::new(waker) },
+        })
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Takes every request sent so far
+    pub fn take(&mut self) -> Result<Vec<Request>> {
+        // clear wakeups first, so that a request sent after this
+        // wakes the next wait rather than being missed
+        let mut buffer = [0; 64];
+        loop {
+            match self.wakeups.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(self.receiver.try_iter().collect())
+    }
+
+    /// The end of the wakeup socket handles write to
+    pub fn waker_fd(&self) -> RawFd {
+        self.handle.waker.as_raw_fd()
+    }
+
+    /// Becomes readable whenever a request is sent
+    pub fn fd(&self) -> RawFd {
+        self.wakeups.as_raw_fd()
+    }
+}
@@ -1,15 +1,33 @@
 extern crate tempfile;
 extern crate regex;
 extern crate rand;
+extern crate libc;
+#[cfg(feature = "async")] extern crate futures;
+#[cfg(feature = "persist")] #[macro_use] extern crate serde;
+#[cfg(feature = "persist")] extern crate serde_json;
+#[cfg(feature = "sqlite")] extern crate rusqlite;
 
 #[macro_use] extern crate lazy_static;
 
+#[cfg(feature = "async")] pub mod asynchronous;
 pub mod bot;
 pub mod command;
+pub mod error;
+pub mod grammar;
+pub mod handle;
+pub mod mock;
+pub mod native;
+#[cfg(feature = "persist")] pub mod persist;
+pub mod schedule;
+pub mod signal;
+pub mod store;
+pub mod transport;
 pub mod zephyr;
 
 pub use bot::Bot;
+pub use bot::Event;
 pub use command::Command;
+pub use command::Dispatch;
 pub use command::Handler;
 pub use command::Scope;
 pub use command::Shape;
@@ -17,3 +35,12 @@ pub use command::Shape;
 pub use zephyr::Notice;
 pub use zephyr::Direction;
 pub use zephyr::Triplet;
+
+pub use transport::Transport;
+
+pub use handle::Handle;
+
+pub use schedule::Cron;
+
+pub use error::Error;
+pub use error::Result;
@@ -0,0 +1,168 @@
+//! In-memory transport for driving bots without a Zephyr server
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use error::Result;
+use native::Kind;
+use transport::Transport;
+use zephyr::{Direction, IncomingData, Notice, Triplet};
+
+/// A transport which replays scripted incoming notices and records
+/// every notice sent through it. Clones share the same queues, so a
+/// test can keep one handle while the bot owns another
+#[derive(Clone)]
+pub struct MockZephyr {
+    subs: Vec<Triplet>,
+    incoming: Rc<RefCell<VecDeque<Notice>>>,
+    sent: Rc<RefCell<Vec<Notice>>>,
+    connected: Rc<Cell<bool>>,
+    reconnects: Rc<Cell<usize>>,
+    closed: Rc<Cell<bool>>,
+}
+
+impl MockZephyr {
+
+    pub fn new(subs: Vec<Triplet>) -> MockZephyr {
+        MockZephyr {
+            subs,
+            incoming: Rc::new(RefCell::new(VecDeque::new())),
+            sent: Rc::new(RefCell::new(vec![])),
+            connected: Rc::new(Cell::new(true)),
+            reconnects: Rc::new(Cell::new(0)),
+            closed: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Queues a notice to be returned by a later `read`
+    pub fn push(&self, notice: Notice) {
+        self.incoming.borrow_mut().push_back(notice);
+    }
+
+    /// Number of scripted notices not yet read
+    pub fn pending(&self) -> usize {
+        self.incoming.borrow().len()
+    }
+
+    /// Every notice sent so far, oldest first
+    pub fn sent(&self) -> Vec<Notice> {
+        self.sent.borrow().clone()
+    }
+
+    /// Like `sent`, but also clears the record
+    pub fn take_sent(&self) -> Vec<Notice> {
+        self.sent.borrow_mut().drain(..).collect()
+    }
+
+    /// Simulates losing the connection: reads and sends fail until
+    /// the transport is reconnected
+    pub fn disconnect(&self) {
+        self.connected.set(false);
+    }
+
+    /// Number of times the transport has been reconnected
+    pub fn reconnects(&self) -> usize {
+        self.reconnects.get()
+    }
+
+    /// Whether the bot has closed the transport
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    fn check_connected(&self) -> Result<()> {
+        if self.connected.get() {
+            Ok(())
+        } else {
+            Err(io::Error::new(ErrorKind::UnexpectedEof, "mock connection lost").into())
+        }
+    }
+}
+
+impl Transport for MockZephyr {
+
+    fn read(&mut self) -> Result<Notice> {
+        self.check_connected()?;
+        self.incoming.borrow_mut().pop_front()
+            .ok_or_else(|| io::Error::new(ErrorKind::WouldBlock, "no scripted notices left").into())
+    }
+
+    /// Returns `None` straight away once the script runs out, rather
+    /// than waiting out the timeout
+    fn read_timeout(&mut self, _timeout: Duration) -> Result<Option<Notice>> {
+        self.check_connected()?;
+        Ok(self.incoming.borrow_mut().pop_front())
+    }
+
+    fn send(&mut self, notice: &Notice) -> Result<()> {
+        self.check_connected()?;
+        self.sent.borrow_mut().push(notice.clone());
+        Ok(())
+    }
+
+    fn subscribe(&mut self, triplet: Triplet) -> Result<()> {
+        if !self.subs.contains(&triplet) {
+            self.subs.push(triplet);
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()> {
+        self.subs.retain(|t| t != triplet);
+        Ok(())
+    }
+
+    fn subs(&self) -> &Vec<Triplet> {
+        &self.subs
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.connected.set(true);
+        self.reconnects.set(self.reconnects.get() + 1);
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.connected.set(false);
+        self.closed.set(true);
+        Ok(())
+    }
+}
+
+/// Builds an authenticated incoming notice, as zwgc would report it
+pub fn incoming(class: &str, instance: &str, sender: &str, body: &str) -> Notice {
+    Notice {
+        opcode:    "".to_string(),
+        direction: Direction::Incoming,
+        class:     class.to_string(),
+        instance:  instance.to_string(),
+        sender:    sender.to_string(),
+        recipient: "".to_string(),
+        zsig:      "".to_string(),
+        body:      body.split('\n').map(|x| x.to_string()).collect(),
+
+        incoming_data: Some(IncomingData {
+            is_auth:     true,
+            is_personal: false,
+            date:        Some(SystemTime::now()),
+            host:        "localhost".to_string(),
+            realm:       "".to_string(),
+            port:        None,
+            kind:        Some(Kind::Acked),
+            uid:         None,
+        }),
+    }
+}
+
+/// Builds an authenticated personal notice from `sender` to `recipient`
+pub fn personal(sender: &str, recipient: &str, body: &str) -> Notice {
+    let mut notice = incoming("MESSAGE", "PERSONAL", sender, body);
+    notice.recipient = recipient.to_string();
+    if let Some(ref mut data) = notice.incoming_data {
+        data.is_personal = true;
+    }
+    notice
+}
@@ -0,0 +1,797 @@
+//! A pure-Rust Zephyr client, speaking the ZephyrGram wire format
+//! to the local hostmanager (`zhm`) instead of spawning zwgc and zwrite
+//!
+//! Only unauthenticated notices are supported; realms which require
+//! Kerberos for subscriptions will refuse them with a SERVNAK
+//!
+//! Notices too large for one packet are sent in fragments, and
+//! fragments received are put back together before being read
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use error::{Error, Result};
+use transport::Transport;
+use zephyr::{self, Direction, IncomingData, Notice, Triplet};
+
+/// Protocol version spoken by this client
+pub const VERSION: &str = "ZEPH0.2";
+
+/// Number of header fields defined by `VERSION`
+const NUM_FIELDS: usize = 17;
+
+/// Largest packet the server will accept
+pub const MAX_PACKET_LEN: usize = 1024;
+
+/// Port the hostmanager listens on
+pub const HM_PORT: u16 = 2104;
+
+/// Most notices being put back together from fragments at once. The
+/// oldest is given up on to make room for another
+const MAX_PARTIAL: usize = 16;
+
+/// Largest notice put back together from fragments
+const MAX_NOTICE_LEN: usize = 1 << 20;
+
+/// Kind of a ZephyrGram, as carried in its header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Unsafe,
+    Unacked,
+    Acked,
+    HmAck,
+    HmCtl,
+    ServAck,
+    ServNak,
+    ClientAck,
+    Stat,
+}
+
+impl Kind {
+
+    pub fn from_u32(kind: u32) -> Option<Kind> {
+        Some(match kind {
+            0 => Kind::Unsafe,
+            1 => Kind::Unacked,
+            2 => Kind::Acked,
+            3 => Kind::HmAck,
+            4 => Kind::HmCtl,
+            5 => Kind::ServAck,
+            6 => Kind::ServNak,
+            7 => Kind::ClientAck,
+            8 => Kind::Stat,
+            _ => return None,
+        })
+    }
+
+    /// Parses the lowercase names zwgc uses for `$kind`
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Some(match name {
+            "unsafe"    => Kind::Unsafe,
+            "unacked"   => Kind::Unacked,
+            "acked"     => Kind::Acked,
+            "hmack"     => Kind::HmAck,
+            "hmctl"     => Kind::HmCtl,
+            "servack"   => Kind::ServAck,
+            "servnak"   => Kind::ServNak,
+            "clientack" => Kind::ClientAck,
+            "stat"      => Kind::Stat,
+            _ => return None,
+        })
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Kind::Unsafe    => 0,
+            Kind::Unacked   => 1,
+            Kind::Acked     => 2,
+            Kind::HmAck     => 3,
+            Kind::HmCtl     => 4,
+            Kind::ServAck   => 5,
+            Kind::ServNak   => 6,
+            Kind::ClientAck => 7,
+            Kind::Stat      => 8,
+        }
+    }
+}
+
+/// Unique id of a ZephyrGram: the sending host and the time it was sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Uid {
+    pub addr: Ipv4Addr,
+    pub secs: u32,
+    pub usecs: u32,
+}
+
+impl Uid {
+
+    pub fn zero() -> Uid {
+        Uid { addr: Ipv4Addr::new(0, 0, 0, 0), secs: 0, usecs: 0 }
+    }
+
+    /// The time this uid was generated at. `usecs` comes straight off
+    /// the wire, so it may be a second or more
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(u64::from(self.secs)) + Duration::from_micros(u64::from(self.usecs))
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.addr.octets().to_vec();
+        bytes.extend_from_slice(&self.secs.to_be_bytes());
+        bytes.extend_from_slice(&self.usecs.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Uid> {
+        if bytes.len() != 12 {
+            return None
+        }
+        Some(Uid {
+            addr: Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+            secs: from_be_bytes(&bytes[4..8]),
+            usecs: from_be_bytes(&bytes[8..12]),
+        })
+    }
+}
+
+/// Error produced when a datagram is not a valid ZephyrGram
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    BadVersion(String),
+    MissingField(&'static str),
+    BadField(&'static str, String),
+    UnknownKind(u32),
+}
+
+impl Display for DecodeError {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::BadVersion(ref v) => write!(f, "unsupported protocol version {:?}", v),
+            DecodeError::MissingField(name) => write!(f, "packet is missing the {} field", name),
+            DecodeError::BadField(name, ref v) => write!(f, "malformed {} field {:?}", name, v),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown notice kind {}", kind),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// A raw ZephyrGram, as it appears on the wire
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub kind: Kind,
+    pub uid: Uid,
+    pub port: u16,
+    pub auth: u32,
+    pub authenticator: String,
+    pub class: String,
+    pub instance: String,
+    pub opcode: String,
+    pub sender: String,
+    pub recipient: String,
+    pub format: String,
+    pub checksum: u32,
+    pub multinotify: String,
+    pub multiuid: Uid,
+    pub other_fields: Vec<String>,
+    pub message: Vec<u8>,
+}
+
+impl Packet {
+
+    pub fn new(kind: Kind, uid: Uid, port: u16) -> Packet {
+        Packet {
+            kind,
+            uid,
+            port,
+            auth: 0,
+            authenticator: String::new(),
+            class: String::new(),
+            instance: String::new(),
+            opcode: String::new(),
+            sender: String::new(),
+            recipient: String::new(),
+            format: String::new(),
+            checksum: 0,
+            multinotify: String::new(),
+            multiuid: uid,
+            other_fields: vec![],
+            message: vec![],
+        }
+    }
+
+    /// Builds an acknowledgement of this packet, such as an HMACK
+    /// or a CLIENTACK
+    pub fn ack(&self, kind: Kind) -> Packet {
+        let mut ack = self.clone();
+        ack.kind = kind;
+        ack.message = vec![];
+        ack
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let header = vec![
+            VERSION.to_string(),
+            ascii32((NUM_FIELDS + self.other_fields.len()) as u32),
+            ascii32(self.kind.to_u32()),
+            ascii_bytes(&self.uid.to_bytes()),
+            format!("0x{:04X}", self.port),
+            ascii32(self.auth),
+            ascii32(self.authenticator.len() as u32),
+            self.authenticator.clone(),
+            self.class.clone(),
+            self.instance.clone(),
+            self.opcode.clone(),
+            self.sender.clone(),
+            self.recipient.clone(),
+            self.format.clone(),
+            ascii32(self.checksum),
+            self.multinotify.clone(),
+            ascii_bytes(&self.multiuid.to_bytes()),
+        ];
+
+        let mut bytes = vec![];
+        for field in header.iter().chain(self.other_fields.iter()) {
+            bytes.extend_from_slice(field.as_bytes());
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(&self.message);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> ::std::result::Result<Packet, DecodeError> {
+        let mut rest = bytes;
+        let mut next = |name: &'static str| -> ::std::result::Result<String, DecodeError> {
+            match rest.iter().position(|&b| b == 0) {
+                Some(end) => {
+                    let field = String::from_utf8_lossy(&rest[..end]).into_owned();
+                    rest = &rest[end + 1..];
+                    Ok(field)
+                },
+                None => Err(DecodeError::MissingField(name)),
+            }
+        };
+
+        let version = next("version")?;
+        if version != VERSION {
+            return Err(DecodeError::BadVersion(version))
+        }
+
+        let num_fields = parse32("num_fields", &next("num_fields")?)? as usize;
+        if num_fields < NUM_FIELDS {
+            return Err(DecodeError::BadField("num_fields", num_fields.to_string()))
+        }
+
+        let kind = parse32("kind", &next("kind")?)?;
+        let kind = Kind::from_u32(kind).ok_or(DecodeError::UnknownKind(kind))?;
+        let uid = parse_uid("uid", &next("uid")?)?;
+        let port = next("port")?;
+        let port = parse32("port", &port)
+            .and_then(|p| if p > 0xFFFF { Err(DecodeError::BadField("port", port)) } else { Ok(p as u16) })?;
+        let auth = parse32("auth", &next("auth")?)?;
+        parse32("authent_len", &next("authent_len")?)?;
+        let authenticator = next("authenticator")?;
+        let class = next("class")?;
+        let instance = next("instance")?;
+        let opcode = next("opcode")?;
+        let sender = next("sender")?;
+        let recipient = next("recipient")?;
+        let format = next("format")?;
+        let checksum = parse32("checksum", &next("checksum")?)?;
+        let multinotify = next("multinotify")?;
+        let multiuid = parse_uid("multiuid", &next("multiuid")?)?;
+
+        let mut other_fields = vec![];
+        for _ in NUM_FIELDS..num_fields {
+            other_fields.push(next("other")?);
+        }
+
+        Ok(Packet {
+            kind, uid, port, auth, authenticator,
+            class, instance, opcode, sender, recipient, format,
+            checksum, multinotify, multiuid, other_fields,
+            message: rest.to_vec(),
+        })
+    }
+
+    /// Where this packet's message starts within the whole notice's,
+    /// and how long that is, if the notice was split into fragments
+    pub fn fragment(&self) -> Option<(usize, usize)> {
+        let mut parts = self.multinotify.splitn(2, '/');
+        let offset = parts.next()?.trim().parse().ok()?;
+        let total = parts.next()?.trim().parse().ok()?;
+        if offset == 0 && total == self.message.len() {
+            None
+        } else {
+            Some((offset, total))
+        }
+    }
+
+    /// Converts an incoming packet into a notice. The message is split
+    /// into the zsig and the body at the first NUL
+    pub fn to_notice(&self) -> Notice {
+        let mut parts = self.message.splitn(2, |&b| b == 0);
+        let zsig = String::from_utf8_lossy(parts.next().unwrap_or(&[])).into_owned();
+        let body = String::from_utf8_lossy(parts.next().unwrap_or(&[]))
+            .trim_end_matches('\0')
+            .replace('\0', "\n");
+
+        Notice {
+            opcode:    self.opcode.clone(),
+            direction: Direction::Incoming,
+            class:     self.class.clone(),
+            instance:  self.instance.clone(),
+            sender:    self.sender.clone(),
+            recipient: self.recipient.clone(),
+            zsig,
+            body:      body.split('\n').map(|x| x.to_string()).collect(),
+
+            incoming_data: Some(IncomingData {
+                is_auth:     self.auth == 1,
+                is_personal: zephyr::is_personal_recipient(&self.recipient),
+                date:        Some(self.uid.time()),
+                host:        self.uid.addr.to_string(),
+                realm:       zephyr::realm_of(&self.recipient, &self.sender),
+                port:        Some(self.port),
+                kind:        Some(self.kind),
+                uid:         Some(self.uid),
+            }),
+        }
+    }
+}
+
+/// A Zephyr connection through the local hostmanager
+pub struct NativeZephyr {
+    socket: UdpSocket,
+    hostmanager: SocketAddr,
+    principal: String,
+    subs: Vec<Triplet>,
+    pending: VecDeque<Notice>,
+    /// Fragments received of notices not yet complete
+    partial: VecDeque<Partial>,
+    last_uid: Uid,
+    timeout: Duration,
+}
+
+/// The fragments received so far of a notice
+struct Partial {
+    first: Packet,
+    total: usize,
+    /// Offset and contents of each fragment
+    pieces: Vec<(usize, Vec<u8>)>,
+}
+
+impl Partial {
+
+    /// The whole notice, once every byte of it has arrived
+    fn assemble(&self) -> Option<Packet> {
+        let mut message = vec![0; self.total];
+        let mut filled = vec![false; self.total];
+        for &(offset, ref piece) in &self.pieces {
+            message[offset..offset + piece.len()].copy_from_slice(piece);
+            for f in &mut filled[offset..offset + piece.len()] {
+                *f = true;
+            }
+        }
+        if !filled.iter().all(|&f| f) {
+            return None
+        }
+
+        let mut packet = self.first.clone();
+        packet.uid = packet.multiuid;
+        packet.multinotify = String::new();
+        packet.message = message;
+        Some(packet)
+    }
+}
+
+impl NativeZephyr {
+
+    /// Connects to the hostmanager on this machine
+    pub fn new(principal: &str, subs: Vec<Triplet>) -> Result<NativeZephyr> {
+        NativeZephyr::connect_to(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), HM_PORT),
+            principal, subs)
+    }
+
+    /// Connects to a hostmanager at the given address
+    pub fn connect_to(hostmanager: SocketAddr, principal: &str, subs: Vec<Triplet>) -> Result<NativeZephyr> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))?;
+
+        let mut zio = NativeZephyr {
+            socket,
+            hostmanager,
+            principal: principal.to_string(),
+            subs: vec![],
+            pending: VecDeque::new(),
+            partial: VecDeque::new(),
+            last_uid: Uid::zero(),
+            timeout: Duration::from_secs(10),
+        };
+
+        if !subs.is_empty() {
+            zio.control("SUBSCRIBE_NODEFS", &subs)?;
+        }
+        zio.subs = subs;
+
+        Ok(zio)
+    }
+
+    /// How long to wait for the hostmanager and server to acknowledge
+    /// a notice
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The local port notices are delivered to
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    fn next_uid(&mut self) -> Uid {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut uid = Uid {
+            addr: Ipv4Addr::new(127, 0, 0, 1),
+            secs: now.as_secs() as u32,
+            usecs: now.subsec_micros(),
+        };
+
+        // uids must be unique, even when sent within the same microsecond
+        if (uid.secs, uid.usecs) <= (self.last_uid.secs, self.last_uid.usecs) {
+            uid.secs = self.last_uid.secs;
+            uid.usecs = self.last_uid.usecs + 1;
+        }
+        self.last_uid = uid;
+        uid
+    }
+
+    fn packet(&mut self, kind: Kind) -> Result<Packet> {
+        let port = self.port()?;
+        let mut packet = Packet::new(kind, self.next_uid(), port);
+        packet.sender = self.principal.clone();
+        Ok(packet)
+    }
+
+    /// Sends a packet and waits until it has been acknowledged by
+    /// both the hostmanager and the server
+    fn send_acked(&mut self, packet: &Packet) -> Result<()> {
+        let bytes = packet.encode();
+        if bytes.len() > MAX_PACKET_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "notice is too large to send").into())
+        }
+        self.socket.send_to(&bytes, self.hostmanager)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut hm_acked = false;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                let what = if hm_acked { "server" } else { "hostmanager" };
+                return Err(io::Error::new(ErrorKind::TimedOut, format!("no acknowledgement from {}", what)).into())
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let received = self.receive();
+            self.socket.set_read_timeout(None)?;
+
+            let (reply, from) = match received {
+                Ok(received) => received,
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            if reply.uid != packet.uid {
+                if let Some(notice) = self.accept(&reply, from)? {
+                    self.pending.push_back(notice);
+                }
+                continue
+            }
+
+            match reply.kind {
+                Kind::HmAck => hm_acked = true,
+                Kind::ServAck => {
+                    return match &reply.message[..] {
+                        b"SENT" | b"SENT\0" => Ok(()),
+                        other => Err(Error::Rejected(format!(
+                            "server did not deliver notice: {}", String::from_utf8_lossy(other)))),
+                    }
+                },
+                Kind::ServNak => {
+                    return Err(Error::Rejected(format!(
+                        "server rejected notice: {}", String::from_utf8_lossy(&reply.message))))
+                },
+                _ => {},
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<(Packet, SocketAddr)> {
+        let mut buffer = [0; MAX_PACKET_LEN * 2];
+        let (len, from) = self.socket.recv_from(&mut buffer)?;
+        Ok((Packet::decode(&buffer[..len])?, from))
+    }
+
+    /// Handles an unsolicited packet, acknowledging it if required.
+    /// Returns the notice it carries or completes, if any
+    fn accept(&mut self, packet: &Packet, from: SocketAddr) -> Result<Option<Notice>> {
+        match packet.kind {
+            Kind::Unsafe | Kind::Unacked => {},
+            Kind::Acked => {
+                self.socket.send_to(&packet.ack(Kind::ClientAck).encode(), from)?;
+            },
+            _ => return Ok(None),
+        }
+        Ok(self.reassemble(packet).map(|packet| packet.to_notice()))
+    }
+
+    /// Adds a packet to the notice it is a fragment of, returning the
+    /// notice once it is complete. Packets which aren't fragments are
+    /// complete already, and malformed fragments are dropped
+    fn reassemble(&mut self, packet: &Packet) -> Option<Packet> {
+        let (offset, total) = match packet.fragment() {
+            Some(fragment) => fragment,
+            None => return Some(packet.clone()),
+        };
+        if total > MAX_NOTICE_LEN || offset + packet.message.len() > total {
+            return None
+        }
+
+        let index = match self.partial.iter().position(|p| p.first.multiuid == packet.multiuid) {
+            Some(index) => index,
+            None => {
+                if self.partial.len() >= MAX_PARTIAL {
+                    self.partial.pop_front();
+                }
+                self.partial.push_back(Partial { first: packet.clone(), total, pieces: vec![] });
+                self.partial.len() - 1
+            },
+        };
+
+        let complete = {
+            let partial = &mut self.partial[index];
+            if partial.total != total {
+                return None
+            }
+            if offset == 0 {
+                partial.first = packet.clone();
+            }
+            partial.pieces.push((offset, packet.message.clone()));
+            partial.assemble()
+        };
+        if complete.is_some() {
+            self.partial.remove(index);
+        }
+        complete
+    }
+
+    /// Sends a notice, in fragments if it doesn't fit in one packet
+    fn send_fragmented(&mut self, packet: &Packet) -> Result<()> {
+        if packet.encode().len() <= MAX_PACKET_LEN {
+            return self.send_acked(packet)
+        }
+
+        // leave room for the widest multinotify this notice can have
+        let total = packet.message.len();
+        let mut header = packet.clone();
+        header.message = vec![];
+        header.multinotify = format!("{}/{}", total, total);
+        let room = MAX_PACKET_LEN.saturating_sub(header.encode().len());
+        if room == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "notice headers are too large to send").into())
+        }
+
+        for (i, chunk) in packet.message.chunks(room).enumerate() {
+            let mut fragment = packet.clone();
+            if i > 0 {
+                fragment.uid = self.next_uid();
+            }
+            fragment.multiuid = packet.uid;
+            fragment.multinotify = format!("{}/{}", i * room, total);
+            fragment.message = chunk.to_vec();
+            self.send_acked(&fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a subscription control message for the given triplets
+    fn control(&mut self, opcode: &str, triplets: &[Triplet]) -> Result<()> {
+        let packet = self.control_packet(opcode, triplets)?;
+        self.send_acked(&packet)
+    }
+
+    fn control_packet(&mut self, opcode: &str, triplets: &[Triplet]) -> Result<Packet> {
+        let mut packet = self.packet(Kind::Acked)?;
+        packet.class = "ZEPHYR_CTL".to_string();
+        packet.instance = "CLIENT".to_string();
+        packet.opcode = opcode.to_string();
+
+        for triplet in triplets {
+            let instance = triplet.instance.as_ref().map(|x| x.as_ref()).unwrap_or("*");
+            let recipient = match triplet.recipient.as_ref().map(|x| x.as_ref()) {
+                None | Some("*") => "",
+                Some("%me%") => &self.principal,
+                Some(recipient) => recipient,
+            };
+            for field in &[&triplet.class[..], instance, recipient] {
+                packet.message.extend_from_slice(field.as_bytes());
+                packet.message.push(0);
+            }
+        }
+
+        Ok(packet)
+    }
+}
+
+impl Transport for NativeZephyr {
+
+    fn read(&mut self) -> Result<Notice> {
+        loop {
+            if let Some(notice) = self.pending.pop_front() {
+                return Ok(notice)
+            }
+            let (packet, from) = self.receive()?;
+            if let Some(notice) = self.accept(&packet, from)? {
+                return Ok(notice)
+            }
+        }
+    }
+
+    fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Notice>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(notice) = self.pending.pop_front() {
+                return Ok(Some(notice))
+            }
+
+            // a zero timeout only takes what has already arrived
+            let now = Instant::now();
+            let received = if now >= deadline {
+                self.socket.set_nonblocking(true)?;
+                let received = self.receive();
+                self.socket.set_nonblocking(false)?;
+                received
+            } else {
+                self.socket.set_read_timeout(Some(deadline - now))?;
+                let received = self.receive();
+                self.socket.set_read_timeout(None)?;
+                received
+            };
+
+            let (packet, from) = match received {
+                Ok(received) => received,
+                Err(Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if let Some(notice) = self.accept(&packet, from)? {
+                return Ok(Some(notice))
+            }
+        }
+    }
+
+    fn send(&mut self, notice: &Notice) -> Result<()> {
+        let mut packet = self.packet(Kind::Acked)?;
+        packet.class = notice.class.clone();
+        packet.instance = notice.instance.clone();
+        packet.opcode = notice.opcode.clone();
+        packet.sender = notice.sender.clone();
+        packet.recipient = notice.recipient.clone();
+        packet.format = "http://zephyr.1ts.org/wiki/df".to_string();
+
+        packet.message.extend_from_slice(notice.zsig.as_bytes());
+        packet.message.push(0);
+        packet.message.extend_from_slice(notice.body.join("\n").as_bytes());
+        packet.message.push(0);
+
+        self.send_fragmented(&packet)
+    }
+
+    fn subscribe(&mut self, triplet: Triplet) -> Result<()> {
+        if self.subs.contains(&triplet) {
+            return Ok(())
+        }
+        self.control("SUBSCRIBE_NODEFS", slice::from_ref(&triplet))?;
+        self.subs.push(triplet);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()> {
+        self.control("UNSUBSCRIBE", slice::from_ref(triplet))?;
+        self.subs.retain(|t| t != triplet);
+        Ok(())
+    }
+
+    fn subs(&self) -> &Vec<Triplet> {
+        &self.subs
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        Some(self.socket.as_raw_fd())
+    }
+
+    /// Cancels every subscription, waiting for the server to agree
+    fn close(&mut self) -> Result<()> {
+        if self.subs.is_empty() {
+            return Ok(())
+        }
+        self.control("CLEARSUB", &[])?;
+        self.subs.clear();
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.pending.clear();
+        self.partial.clear();
+        let subs = self.subs.clone();
+        if subs.is_empty() {
+            return Ok(())
+        }
+        self.control("SUBSCRIBE_NODEFS", &subs)
+    }
+}
+
+impl Drop for NativeZephyr {
+    fn drop(&mut self) {
+        // best effort, without waiting for acknowledgement: the server
+        // drops our subscriptions eventually anyway
+        if !self.subs.is_empty() {
+            if let Ok(packet) = self.control_packet("CLEARSUB", &[]) {
+                let _ = self.socket.send_to(&packet.encode(), self.hostmanager);
+            }
+        }
+    }
+}
+
+fn from_be_bytes(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | u32::from(b))
+}
+
+fn ascii32(val: u32) -> String {
+    format!("0x{:08X}", val)
+}
+
+/// Hex-encodes bytes in 4-byte "0x" groups separated by spaces
+fn ascii_bytes(bytes: &[u8]) -> String {
+    bytes.chunks(4)
+        .map(|chunk| {
+            let hex = chunk.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+            format!("0x{}", hex)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_ascii(name: &'static str, field: &str) -> ::std::result::Result<Vec<u8>, DecodeError> {
+    let bad = || DecodeError::BadField(name, field.to_string());
+
+    let mut bytes = vec![];
+    for group in field.split_whitespace() {
+        let digits = group.trim_start_matches("0x").trim_start_matches("0X");
+        if digits.len() % 2 != 0 {
+            return Err(bad())
+        }
+        for i in (0..digits.len()).step_by(2) {
+            let byte = digits.get(i..i + 2).ok_or_else(bad)?;
+            bytes.push(u8::from_str_radix(byte, 16).map_err(|_| bad())?);
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse32(name: &'static str, field: &str) -> ::std::result::Result<u32, DecodeError> {
+    let bytes = parse_ascii(name, field)?;
+    if bytes.is_empty() || bytes.len() > 4 {
+        return Err(DecodeError::BadField(name, field.to_string()))
+    }
+    Ok(from_be_bytes(&bytes))
+}
+
+fn parse_uid(name: &'static str, field: &str) -> ::std::result::Result<Uid, DecodeError> {
+    let bytes = parse_ascii(name, field)?;
+    Uid::from_bytes(&bytes).ok_or_else(|| DecodeError::BadField(name, field.to_string()))
+}
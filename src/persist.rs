@@ -0,0 +1,94 @@
+//! Saving a bot's state across restarts, enabled by the `persist`
+//! feature. See `Builder::persist_to`
+
+use std::fs::File;
+use std::io::{self, ErrorKind, BufReader, Write};
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use tempfile::NamedTempFile;
+
+use bot::State;
+use error::Result;
+use zephyr::Triplet;
+
+/// Everything saved about a bot
+#[derive(Serialize)]
+struct Saving<'a, E: 'a> {
+    class: &'a str,
+    instance: &'a str,
+    subs: &'a [Triplet],
+    extra: &'a E,
+}
+
+/// Everything loaded about a bot
+#[derive(Deserialize)]
+pub struct Saved<E> {
+    pub class: String,
+    pub instance: String,
+    pub subs: Vec<Triplet>,
+    pub extra: E,
+}
+
+/// Writes the location, subscriptions and extra state of a bot to
+/// `path`. A crash while saving leaves the previous save intact
+pub fn save<E: Serialize>(path: &Path, state: &State<E>) -> Result<()> {
+    let subs = state.subs();
+    let saving = Saving {
+        class: &state.class,
+        instance: &state.instance,
+        subs: &subs,
+        extra: state.extra_ref(),
+    };
+
+    write_atomically(path, |file| {
+        serde_json::to_writer_pretty(file, &saving).map_err(io::Error::from)
+    })
+}
+
+/// Replaces the file at `path` with whatever `write` writes. Readers
+/// see either the old file or the new one, never a partial write
+pub fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+    where F: FnOnce(&mut dyn Write) -> io::Result<()> {
+    // the temporary file must be on the same filesystem to be renamed
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    write(&mut file)?;
+    file.flush()?;
+    file.sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Reads a bot's state from `path`, or `None` if nothing has been
+/// saved there yet
+pub fn load<E: DeserializeOwned>(path: &Path) -> Result<Option<Saved<E>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let saved = serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from)?;
+    Ok(Some(saved))
+}
+
+/// Restores a loaded state into a freshly built bot. Saved
+/// subscriptions replace those the bot was built with, so that
+/// leaving one of those is remembered too
+pub fn restore<E>(state: &mut State<E>, saved: Saved<E>) -> Result<()> {
+    state.move_to(Triplet::of_instance(&saved.class, &saved.instance));
+    let built = state.subs().clone();
+    for sub in built.iter().filter(|sub| !saved.subs.contains(sub)) {
+        state.unsubscribe(sub)?;
+    }
+    for sub in saved.subs {
+        state.subscribe(sub)?;
+    }
+    *state.extra_mut() = saved.extra;
+    Ok(())
+}
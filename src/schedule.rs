@@ -0,0 +1,304 @@
+//! Timers and scheduled tasks run by the bot's event loop
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bot::State;
+
+/// An action run by a repeating timer
+pub type TimerAction<E> = Box<dyn Fn(&mut State<E>)>;
+
+/// An action run once by `State::schedule_after`
+pub type OnceAction<E> = Box<dyn FnOnce(&mut State<E>)>;
+
+enum Job<E> {
+    Once(OnceAction<E>),
+    Every(Duration, TimerAction<E>),
+    At(Cron, TimerAction<E>),
+}
+
+/// When a timer is next due. Delays and periods are measured on the
+/// monotonic clock, so that changes to the system clock don't make
+/// them fire early or late, but cron expressions follow the system clock
+#[derive(Clone, Copy)]
+enum Due {
+    In(Instant),
+    At(SystemTime),
+}
+
+impl Due {
+
+    /// When this is due on the monotonic clock, as of `now` on it and
+    /// `wall` on the system clock
+    fn instant(self, now: Instant, wall: SystemTime) -> Instant {
+        match self {
+            Due::In(due) => due,
+            Due::At(due) => now + due.duration_since(wall).unwrap_or_default(),
+        }
+    }
+}
+
+struct Timer<E> {
+    due: Due,
+    job: Job<E>,
+}
+
+/// The timers of a bot
+pub struct Timers<E> {
+    timers: Vec<Timer<E>>,
+}
+
+impl<E> Default for Timers<E> {
+    fn default() -> Timers<E> {
+        Timers { timers: vec![] }
+    }
+}
+
+impl<E> Timers<E> {
+
+    pub fn new() -> Timers<E> {
+        Timers::default()
+    }
+
+    /// Runs an action once, after a delay
+    pub fn after(&mut self, delay: Duration, action: OnceAction<E>) {
+        self.timers.push(Timer { due: Due::In(Instant::now() + delay), job: Job::Once(action) });
+    }
+
+    /// Runs an action repeatedly, first after one period
+    pub fn every(&mut self, period: Duration, action: TimerAction<E>) {
+        self.timers.push(Timer { due: Due::In(Instant::now() + period), job: Job::Every(period, action) });
+    }
+
+    /// Runs an action whenever the local time matches a cron
+    /// expression. Expressions which never match are ignored
+    pub fn at(&mut self, cron: Cron, action: TimerAction<E>) {
+        if let Some(due) = cron.next_after(SystemTime::now()) {
+            self.timers.push(Timer { due: Due::At(due), job: Job::At(cron, action) });
+        }
+    }
+
+    /// When the earliest timer is due, if there are any
+    pub fn next_due(&self) -> Option<Instant> {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        self.timers.iter().map(|t| t.due.instant(now, wall)).min()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+}
+
+/// Runs every timer of `state` which is due, rescheduling those that
+/// repeat. Timers scheduled by the actions themselves are kept
+pub fn run_due<E>(state: &mut State<E>) {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+
+    let mut due = {
+        let timers = &mut state.timers_mut().timers;
+        let (due, waiting) = mem::take(timers).into_iter()
+            .map(|t| (t.due.instant(now, wall), t))
+            .partition::<Vec<_>, _>(|&(at, _)| at <= now);
+        *timers = waiting.into_iter().map(|(_, t)| t).collect();
+        due
+    };
+    due.sort_by_key(|&(at, _)| at);
+
+    for (at, timer) in due {
+        match timer.job {
+            Job::Once(action) => action(state),
+            Job::Every(period, action) => {
+                action(state);
+
+                // don't try to catch up on ticks missed while busy
+                let mut next = at + period;
+                if next <= now {
+                    next = now + period;
+                }
+                state.timers_mut().timers.push(Timer { due: Due::In(next), job: Job::Every(period, action) });
+            },
+            Job::At(cron, action) => {
+                action(state);
+                if let Some(next) = cron.next_after(wall) {
+                    state.timers_mut().timers.push(Timer { due: Due::At(next), job: Job::At(cron, action) });
+                }
+            },
+        }
+    }
+}
+
+/// Error produced when a cron expression is malformed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronError {
+    pub expr: String,
+    pub reason: String,
+}
+
+impl Display for CronError {
+
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid cron expression {:?}: {}", self.expr, self.reason)
+    }
+}
+
+impl error::Error for CronError {}
+
+/// A standard five-field cron expression: minute, hour, day of month,
+/// month and day of week. Each field is `*`, a number, a range `a-b`,
+/// any of those with a step `/n`, or a comma-separated list of them.
+/// Times are evaluated in the local timezone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+
+    pub fn parse(expr: &str) -> Result<Cron, CronError> {
+        let error = |reason: String| CronError { expr: expr.to_string(), reason };
+
+        let fields = expr.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(error(format!("expected 5 fields, found {}", fields.len())))
+        }
+
+        let field = |i: usize, min: usize, max: usize| {
+            parse_field(fields[i], min, max).map_err(&error)
+        };
+
+        let mut weekdays = field(4, 0, 7)?;
+        // both 0 and 7 are Sunday
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+
+        Ok(Cron {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            weekdays,
+            // "*/2" is as unrestricted as "*" as far as cron is concerned
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+
+    /// The first time after `time` matching this expression, to the
+    /// minute, or `None` if it never matches
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+        let mut tm = localtime(secs - secs % 60 + 60)?;
+
+        // four years covers every day of every month
+        for _ in 0..(4 * 366 * 24 + 60) {
+            if !self.months[tm.tm_mon as usize + 1] {
+                tm.tm_mon += 1;
+                tm.tm_mday = 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+            } else if !self.matches_day(&tm) {
+                tm.tm_mday += 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+            } else if !self.hours[tm.tm_hour as usize] {
+                tm.tm_hour += 1;
+                tm.tm_min = 0;
+            } else if !self.minutes[tm.tm_min as usize] {
+                tm.tm_min += 1;
+            } else {
+                let secs = mktime(&mut tm)?;
+                return Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+            }
+            tm = localtime(mktime(&mut tm)?)?;
+        }
+        None
+    }
+
+    fn matches_day(&self, tm: &libc::tm) -> bool {
+        let day = self.days[tm.tm_mday as usize];
+        let weekday = self.weekdays[tm.tm_wday as usize];
+
+        // as in cron, when both are restricted either may match
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut set = vec![false; max + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => {
+                let step = part[i + 1..].parse::<usize>()
+                    .map_err(|_| format!("bad step in {:?}", part))?;
+                (&part[..i], step)
+            },
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("zero step in {:?}", part))
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let number = |x: &str| x.parse::<usize>().map_err(|_| format!("bad number in {:?}", part));
+            match range.find('-') {
+                Some(i) => (number(&range[..i])?, number(&range[i + 1..])?),
+                None => {
+                    let n = number(range)?;
+                    // "5/15" means from 5 to the end, every 15
+                    (n, if step > 1 { max } else { n })
+                },
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!("{:?} is outside {}-{}", part, min, max))
+        }
+
+        for i in (start..=end).step_by(step) {
+            set[i] = true;
+        }
+    }
+
+    Ok(set)
+}
+
+fn localtime(secs: libc::time_t) -> Option<libc::tm> {
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    let result = unsafe { libc::localtime_r(&secs, &mut tm) };
+    if result.is_null() {
+        None
+    } else {
+        Some(tm)
+    }
+}
+
+fn mktime(tm: &mut libc::tm) -> Option<libc::time_t> {
+    tm.tm_sec = 0;
+    tm.tm_isdst = -1;
+    let secs = unsafe { libc::mktime(tm) };
+    if secs < 0 {
+        None
+    } else {
+        Some(secs)
+    }
+}
@@ -0,0 +1,78 @@
+//! Stopping a running bot cleanly on SIGINT and SIGTERM
+
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use error::Result;
+
+/// Signals which ask a bot to shut down
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+static CAUGHT: AtomicBool = AtomicBool::new(false);
+
+/// Written to when a signal is caught, to wake the event loop
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    CAUGHT.store(true, Ordering::SeqCst);
+
+    // only async-signal-safe calls here: a failed write just means
+    // the event loop notices a little later
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = 0u8;
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+/// Whether a signal has been caught since the last call
+pub fn take_caught() -> bool {
+    CAUGHT.swap(false, Ordering::SeqCst)
+}
+
+/// Catches SIGINT and SIGTERM for as long as it lives, restoring the
+/// previous handlers when dropped. Only one bot in a process should
+/// catch signals at a time
+pub struct SignalGuard {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+
+impl SignalGuard {
+
+    /// Starts catching signals, writing a byte to `wake_fd`, which
+    /// must be non-blocking, whenever one is caught
+    pub fn install(wake_fd: RawFd) -> Result<SignalGuard> {
+        CAUGHT.store(false, Ordering::SeqCst);
+        WAKE_FD.store(wake_fd, Ordering::SeqCst);
+
+        let mut guard = SignalGuard { previous: vec![] };
+        for &signal in SIGNALS.iter() {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut previous: libc::sigaction = mem::zeroed();
+                if libc::sigaction(signal, &action, &mut previous) != 0 {
+                    // dropping the guard restores those already changed
+                    return Err(io::Error::last_os_error().into())
+                }
+                guard.previous.push((signal, previous));
+            }
+        }
+        Ok(guard)
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for &(signal, ref previous) in self.previous.iter() {
+            unsafe { libc::sigaction(signal, previous, ptr::null_mut()) };
+        }
+        WAKE_FD.store(-1, Ordering::SeqCst);
+    }
+}
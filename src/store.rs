@@ -0,0 +1,314 @@
+//! Namespaced key-value storage for common bot state, such as
+//! per-user counters, on a pluggable backend
+//!
+//! Values are strings; `Namespace::get_as` and `Namespace::set` convert
+//! anything with `FromStr` and `ToString`.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+
+use error::Result;
+use zephyr::Triplet;
+
+/// Where a `Store` keeps its data. Keys are unique within a namespace
+pub trait Backend {
+
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<String>>;
+
+    fn set(&mut self, namespace: &str, key: &str, value: &str) -> Result<()>;
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<()>;
+
+    /// Every key in a namespace, in order
+    fn keys(&self, namespace: &str) -> Result<Vec<String>>;
+}
+
+/// A backend which forgets everything when the bot stops
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    data: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl MemoryBackend {
+
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+        Ok(self.data.get(namespace).and_then(|ns| ns.get(key)).cloned())
+    }
+
+    fn set(&mut self, namespace: &str, key: &str, value: &str) -> Result<()> {
+        self.data.entry(namespace.to_string()).or_default()
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<()> {
+        let now_empty = match self.data.get_mut(namespace) {
+            Some(ns) => {
+                ns.remove(key);
+                ns.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            self.data.remove(namespace);
+        }
+        Ok(())
+    }
+
+    fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+        Ok(self.data.get(namespace).map(|ns| ns.keys().cloned().collect()).unwrap_or_default())
+    }
+}
+
+/// A key-value store, split into namespaces for users, triplets, and
+/// the bot as a whole
+pub struct Store {
+    backend: RefCell<Box<dyn Backend>>,
+}
+
+impl Store {
+
+    pub fn new<B>(backend: B) -> Store
+        where B: Backend + 'static {
+        Store::from_box(Box::new(backend))
+    }
+
+    pub fn from_box(backend: Box<dyn Backend>) -> Store {
+        Store { backend: RefCell::new(backend) }
+    }
+
+    /// Data about a user, by their principal
+    pub fn user(&self, user: &str) -> Namespace<'_> {
+        self.namespace(format!("user:{}", user))
+    }
+
+    /// Data about a class, instance and recipient. Classes are
+    /// case-insensitive, as in Zephyr
+    pub fn triplet(&self, triplet: &Triplet) -> Namespace<'_> {
+        self.namespace(format!("triplet:{}/{}/{}",
+            triplet.class.to_lowercase(),
+            triplet.instance.as_ref().map(|x| x.as_ref()).unwrap_or("*"),
+            triplet.recipient.as_ref().map(|x| x.as_ref()).unwrap_or("*")))
+    }
+
+    /// Data about the bot as a whole
+    pub fn global(&self) -> Namespace<'_> {
+        self.namespace("global".to_string())
+    }
+
+    /// A namespace with any other name. Names containing a `:` may
+    /// clash with `user`, `triplet` and `global`
+    pub fn namespace(&self, name: String) -> Namespace<'_> {
+        Namespace { store: self, name }
+    }
+}
+
+impl Default for Store {
+    fn default() -> Store {
+        Store::new(MemoryBackend::new())
+    }
+}
+
+/// One namespace of a `Store`
+pub struct Namespace<'a> {
+    store: &'a Store,
+    name: String,
+}
+
+impl<'a> Namespace<'a> {
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.backend.borrow().get(&self.name, key)
+    }
+
+    /// Like `get`, but parses the value
+    pub fn get_as<T>(&self, key: &str) -> Result<Option<T>>
+        where T: FromStr, T::Err: Display {
+        match self.get(key)? {
+            Some(value) => value.parse().map(Some).map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData,
+                    format!("bad value for {} in {}: {}", key, self.name, e)).into()
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set<T>(&self, key: &str, value: T) -> Result<()>
+        where T: ToString {
+        self.store.backend.borrow_mut().set(&self.name, key, &value.to_string())
+    }
+
+    /// Replaces a value with a function of the old one, returning the
+    /// new value. Handy for counters
+    pub fn update<T, F>(&self, key: &str, f: F) -> Result<T>
+        where T: FromStr + ToString, T::Err: Display, F: FnOnce(Option<T>) -> T {
+        let value = f(self.get_as(key)?);
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.store.backend.borrow_mut().remove(&self.name, key)
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        self.store.backend.borrow().keys(&self.name)
+    }
+}
+
+#[cfg(feature = "persist")]
+pub use self::json::JsonBackend;
+
+#[cfg(feature = "persist")]
+mod json {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::{self, BufReader, ErrorKind};
+    use std::path::{Path, PathBuf};
+
+    use serde_json;
+
+    use error::Result;
+    use persist;
+    use super::{Backend, MemoryBackend};
+
+    /// A backend kept in memory and written out to a JSON file, which
+    /// is replaced atomically, on every change
+    pub struct JsonBackend {
+        path: PathBuf,
+        memory: MemoryBackend,
+    }
+
+    impl JsonBackend {
+
+        /// Opens the store at `path`, which is created on the first
+        /// change if it doesn't exist
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<JsonBackend> {
+            let path = path.as_ref().to_path_buf();
+            let data = match File::open(&path) {
+                Ok(file) => serde_json::from_reader(BufReader::new(file)).map_err(io::Error::from)?,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e.into()),
+            };
+            Ok(JsonBackend { path, memory: MemoryBackend { data } })
+        }
+
+        fn write(&self) -> Result<()> {
+            persist::write_atomically(&self.path, |file| {
+                serde_json::to_writer_pretty(file, &self.memory.data).map_err(io::Error::from)
+            })
+        }
+    }
+
+    impl Backend for JsonBackend {
+
+        fn get(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+            self.memory.get(namespace, key)
+        }
+
+        fn set(&mut self, namespace: &str, key: &str, value: &str) -> Result<()> {
+            self.memory.set(namespace, key, value)?;
+            self.write()
+        }
+
+        fn remove(&mut self, namespace: &str, key: &str) -> Result<()> {
+            self.memory.remove(namespace, key)?;
+            self.write()
+        }
+
+        fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+            self.memory.keys(namespace)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteBackend;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::io;
+    use std::path::Path;
+
+    use rusqlite::{self, Connection, OptionalExtension};
+
+    use error::{Error, Result};
+    use super::Backend;
+
+    /// A backend in an SQLite database, in a table named `zpet_store`
+    pub struct SqliteBackend {
+        db: Connection,
+    }
+
+    impl SqliteBackend {
+
+        /// Opens or creates the database at `path`
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteBackend> {
+            SqliteBackend::with_connection(Connection::open(path).map_err(sql)?)
+        }
+
+        /// Uses an already open database, creating the table if needed
+        pub fn with_connection(db: Connection) -> Result<SqliteBackend> {
+            db.execute_batch(
+                "CREATE TABLE IF NOT EXISTS zpet_store (
+                    namespace TEXT NOT NULL,
+                    key       TEXT NOT NULL,
+                    value     TEXT NOT NULL,
+                    PRIMARY KEY (namespace, key)
+                )").map_err(sql)?;
+            Ok(SqliteBackend { db })
+        }
+    }
+
+    fn sql(e: rusqlite::Error) -> Error {
+        io::Error::other(e).into()
+    }
+
+    impl Backend for SqliteBackend {
+
+        fn get(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+            self.db.query_row(
+                "SELECT value FROM zpet_store WHERE namespace = ?1 AND key = ?2",
+                [namespace, key],
+                |row| row.get(0),
+            ).optional().map_err(sql)
+        }
+
+        fn set(&mut self, namespace: &str, key: &str, value: &str) -> Result<()> {
+            self.db.execute(
+                "INSERT OR REPLACE INTO zpet_store (namespace, key, value) VALUES (?1, ?2, ?3)",
+                [namespace, key, value],
+            ).map_err(sql)?;
+            Ok(())
+        }
+
+        fn remove(&mut self, namespace: &str, key: &str) -> Result<()> {
+            self.db.execute(
+                "DELETE FROM zpet_store WHERE namespace = ?1 AND key = ?2",
+                [namespace, key],
+            ).map_err(sql)?;
+            Ok(())
+        }
+
+        fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+            let mut query = self.db.prepare(
+                "SELECT key FROM zpet_store WHERE namespace = ?1 ORDER BY key").map_err(sql)?;
+            let keys = query.query_map([namespace], |row| row.get(0)).map_err(sql)?;
+            keys.collect::<::std::result::Result<Vec<String>, _>>().map_err(sql)
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Abstraction over the connection a bot talks through
+
+use std::cmp;
+use std::io::{self, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use error::Result;
+
+use zephyr::{Notice, Triplet};
+
+/// A source and sink of Zephyr notices. `zephyr::Zephyr` is the
+/// default implementation, but a bot may be run against any other
+/// backend, such as a test double
+pub trait Transport {
+
+    /// Blocks until the next incoming notice is available
+    fn read(&mut self) -> Result<Notice>;
+
+    /// Like `read`, but gives up once `timeout` has passed without
+    /// a notice. This must not block for longer, as the bot and
+    /// `asynchronous::Notices` call it with a zero timeout to check
+    /// for notices without stalling everything else
+    fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Notice>>;
+
+    /// Sends an outgoing notice
+    fn send(&mut self, notice: &Notice) -> Result<()>;
+
+    /// Adds a subscription, taking effect immediately. Subscribing
+    /// to a triplet twice has no further effect
+    fn subscribe(&mut self, triplet: Triplet) -> Result<()>;
+
+    /// Removes a subscription, taking effect immediately
+    fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()>;
+
+    /// The current subscriptions of this transport
+    fn subs(&self) -> &Vec<Triplet>;
+
+    /// A file descriptor which becomes readable when a notice may be
+    /// available, so that the bot can wait on it alongside its other
+    /// sources of events. The bot only waits on it once `read_timeout`
+    /// has returned `None`, so notices buffered by the transport itself
+    /// are never missed. Without one, the bot checks `read_timeout`
+    /// every few milliseconds instead
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Re-establishes a connection lost with an error for which
+    /// `Error::is_disconnect` holds, including all subscriptions
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Releases everything the transport holds, such as child processes
+    /// and temporary files, when the bot shuts down. Dropping the
+    /// transport does the same, but can't report errors
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How often to check transports which can't be waited on, as they
+/// have no `fd`
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Waits until any of `fds` is readable or closed, or until `timeout`
+/// passes; `None` waits forever. Returns whether one was readable.
+/// Being interrupted by a signal counts as a timeout
+pub fn wait_readable(fds: &[RawFd], timeout: Option<Duration>) -> Result<bool> {
+    let mut polls = fds.iter()
+        .map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 })
+        .collect::<Vec<_>>();
+
+    let millis = match timeout {
+        // round up, so that short timeouts don't spin
+        Some(timeout) => cmp::min(timeout.as_micros().div_ceil(1000), libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+
+    match unsafe { libc::poll(polls.as_mut_ptr(), polls.len() as libc::nfds_t, millis) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e.into())
+            }
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Exponential backoff between reconnection attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff { initial, max, current: initial }
+    }
+
+    /// The delay before the next attempt. Each call doubles the
+    /// delay, up to the maximum
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+        delay
+    }
+
+    /// Starts again from the initial delay
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(300))
+    }
+}
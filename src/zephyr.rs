@@ -2,16 +2,24 @@
 
 use std::result::{Result as SResult};
 use std::fmt::{Formatter, Display, Error};
-use std::io::{Read, Write, Result, BufReader};
+use std::error;
+use std::io::{self, Read, Write, Seek, SeekFrom, ErrorKind};
 use std::process::*;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::os::unix::io::{AsRawFd, RawFd};
 
+use std::collections::VecDeque;
 use std::mem;
+use std::path::{Path, PathBuf};
 
 use tempfile::NamedTempFile;
 
 use regex::Regex;
 
+use error::{Error as ZError, Result};
+use native::{Kind, Uid};
+use transport::{wait_readable, Transport};
+
 /// Enum representing a notice direction
 #[derive(Clone, Debug)]
 pub enum Direction {
@@ -26,6 +34,8 @@ pub struct Notice {
     pub class:     String,
     pub instance:  String,
     pub sender:    String,
+    /// Empty for notices sent to everyone on the class
+    pub recipient: String,
     pub zsig:      String,
     pub body:      Vec<String>,
 
@@ -35,9 +45,18 @@ pub struct Notice {
 /// Data unique to an incoming zephyrgram
 #[derive(Clone, Debug)]
 pub struct IncomingData {
-    pub is_auth:   bool,
-    pub date:      Duration,
-    pub host:      String,
+    pub is_auth:     bool,
+    /// Whether the notice was addressed to us, rather than to a class
+    pub is_personal: bool,
+    /// When the notice was sent, if it could be determined
+    pub date:        Option<SystemTime>,
+    pub host:        String,
+    /// Realm of the recipient, or of the sender for broadcasts
+    pub realm:       String,
+    pub port:        Option<u16>,
+    pub kind:        Option<Kind>,
+    /// Only known when using the native protocol
+    pub uid:         Option<Uid>,
 }
 
 impl Notice {
@@ -69,6 +88,7 @@ impl Notice {
             class:     class.to_string(),
             instance:  instance.to_string(),
             sender:    sender.to_string(),
+            recipient: String::new(),
             zsig:      zsig.to_string(),
             body:      wrap_lines(wrap, body),
 
@@ -77,17 +97,45 @@ impl Notice {
     }
 
     pub fn make_reply(&self, sender: &str, zsig: &str, body: &str) -> Notice {
-        self.triplet().make_reply(sender, zsig, body)
+        self.reply_triplet().make_reply(sender, zsig, body)
     }
 
     pub fn triplet(&self) -> Triplet {
         Triplet::of_instance(&self.class, &self.instance)
     }
 
+    /// Where a reply to this notice should go: back to the sender
+    /// alone for personals, otherwise to the same class and instance
+    pub fn reply_triplet(&self) -> Triplet {
+        if self.is_personal() {
+            Triplet::new(&self.class, &self.instance, &self.sender)
+        } else {
+            self.triplet()
+        }
+    }
+
     pub fn was_sent_to(&self, triplet: &Triplet) -> bool {
-        triplet.class == self.class &&
-            (triplet.instance.is_none() ||
-                triplet.instance.as_ref().unwrap() == &self.instance)
+        let instance_matches = match triplet.instance.as_ref().map(|x| x.as_ref()) {
+            None | Some("*") => true,
+            Some(instance) => instance == self.instance,
+        };
+        let recipient_matches = match triplet.recipient.as_ref().map(|x| x.as_ref()) {
+            None | Some("*") => true,
+            Some("%me%") => self.is_personal(),
+            Some(recipient) => recipient == self.recipient,
+        };
+        // the server treats class names case-insensitively
+        triplet.class.to_lowercase() == self.class.to_lowercase() &&
+            instance_matches && recipient_matches
+    }
+
+    /// Whether this notice is addressed to a single user rather than to
+    /// a class. For incoming notices that user is us
+    pub fn is_personal(&self) -> bool {
+        match self.incoming_data {
+            Some(ref data) => data.is_personal,
+            None => is_personal_recipient(&self.recipient),
+        }
     }
 
     pub fn is_auth(&self) -> bool {
@@ -96,10 +144,23 @@ impl Notice {
             None => false,
         }
     }
+
+    /// When an incoming notice was sent
+    pub fn date(&self) -> Option<SystemTime> {
+        self.incoming_data.as_ref().and_then(|data| data.date)
+    }
+
+    /// How long ago an incoming notice was sent. Clock skew between
+    /// hosts may make a recent notice appear to be from the future,
+    /// in which case this is zero
+    pub fn age(&self) -> Option<Duration> {
+        self.date().map(|date| SystemTime::now().duration_since(date).unwrap_or_default())
+    }
 }
 
 /// Struct representing a Zephyr triplet
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "persist", derive(Serialize, Deserialize))]
 pub struct Triplet {
     pub class: String,
     pub instance: Option<String>,
@@ -116,6 +177,16 @@ impl Triplet {
         }
     }
 
+    /// Matches every personal notice sent to us, as `message,*,%me%`
+    /// does in a zwgc subscription file
+    pub fn personal() -> Triplet {
+        Triplet {
+            class: "message".to_string(),
+            instance: None,
+            recipient: Some("%me%".to_string()),
+        }
+    }
+
     pub fn of_instance(class: &str, instance: &str) -> Triplet {
         Triplet {
             class: class.to_string(),
@@ -132,10 +203,24 @@ impl Triplet {
         }
     }
 
+    /// Triplet of a personal notice to `user`, as sent by `zwrite user`
+    pub fn to_user(user: &str) -> Triplet {
+        Triplet::new("MESSAGE", "PERSONAL", user)
+    }
+
+    /// Makes a notice to this triplet. Like zwrite, a missing instance
+    /// means "personal"
     pub fn make_reply(&self, sender: &str, zsig: &str, body: &str) -> Notice {
-        Notice::new_outgoing("AUTO", &self.class,
-                             &self.instance.as_ref().unwrap_or(&"personal".to_string()),
-                             sender, zsig, body)
+        let mut notice = Notice::new_outgoing(
+            "AUTO", &self.class,
+            self.instance.as_ref().map(|x| x.as_ref()).unwrap_or("personal"),
+            sender, zsig, body);
+
+        match self.recipient.as_ref().map(|x| x.as_ref()) {
+            None | Some("*") => {},
+            Some(recipient) => notice.recipient = recipient.to_string(),
+        }
+        notice
     }
 }
 
@@ -153,49 +238,106 @@ impl Display for Triplet {
 /// and access to zwrite
 pub struct Zephyr {
     subs: Vec<Triplet>,
+    /// The zwgc program run
+    zwgc: PathBuf,
     format_file: Option<NamedTempFile>,
     sub_file: Option<NamedTempFile>,
+    wg_file: Option<NamedTempFile>,
     child: Option<Child>,
+    frames: FrameBuffer,
+    /// Notices read while waiting for zwgc to start
+    pending: VecDeque<String>,
 }
 
 impl Zephyr {
 
     pub fn new(subs: Vec<Triplet>) -> Result<Zephyr> {
+        Zephyr::with_zwgc(subs, "zwgc")
+    }
 
-        let mut format_file = NamedTempFile::new().expect("failed to create temporary file");
-        let mut sub_file = NamedTempFile::new().expect("failed to create temporary file");
-
-        write!(format_file, "{}", FORMAT)?;
+    /// Like `new`, but runs the zwgc at `zwgc` instead of the one on
+    /// the `PATH`
+    pub fn with_zwgc<P: AsRef<Path>>(subs: Vec<Triplet>, zwgc: P) -> Result<Zephyr> {
 
-        for sub in subs.iter() {
-            write!(sub_file, "{}\n", sub)?;
-        }
+        let mut format_file = NamedTempFile::new()?;
+        let sub_file = NamedTempFile::new()?;
+        let wg_file = NamedTempFile::new()?;
 
-        let mut zio = Zephyr { subs, format_file: Some(format_file), sub_file: Some(sub_file), child: None };
-        zio.restart()?;
+        write!(format_file, "{}", FORMAT)?;
 
-        // read the first message and discard it
-        zio.read()?;
+        let mut zio = Zephyr {
+            subs,
+            zwgc: zwgc.as_ref().to_path_buf(),
+            format_file: Some(format_file),
+            sub_file: Some(sub_file),
+            wg_file: Some(wg_file),
+            child: None,
+            frames: FrameBuffer::new(),
+            pending: VecDeque::new(),
+        };
+        zio.write_subs()?;
+        zio.connect()?;
 
         eprintln!("connected to zephyr!");
 
         Ok(zio)
     }
 
+    fn write_subs(&mut self) -> Result<()> {
+        let sub_file = self.sub_file.as_mut().ok_or_else(closed)?;
+        sub_file.seek(SeekFrom::Start(0))?;
+        sub_file.set_len(0)?;
+
+        for sub in self.subs.iter() {
+            writeln!(sub_file, "{}", sub)?;
+        }
+        sub_file.flush()?;
+        Ok(())
+    }
+
+    /// Restarts zwgc and waits until it is running, so that notices
+    /// sent from then on are received
+    fn connect(&mut self) -> Result<()> {
+        self.restart()?;
+
+        // zwgc formats a startup notice of its own before any others,
+        // which FORMAT prints as READY
+        let deadline = Instant::now() + READY_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.next_frame(Some(remaining))? {
+                Some(ref frame) if frame == READY => return Ok(()),
+                Some(frame) => self.pending.push_back(frame),
+                None => {
+                    self.kill()?;
+                    return Err(io::Error::new(ErrorKind::TimedOut, "zwgc did not start in time").into())
+                },
+            }
+        }
+    }
+
     pub fn subs(&self) -> &Vec<Triplet> {
         &self.subs
     }
 
+    /// Starts zwgc again, without waiting for it to be ready. Notices
+    /// the old zwgc printed in full are kept
     pub fn restart(&mut self) -> Result<()> {
         self.kill()?;
+        while let Some(frame) = self.frames.next_frame() {
+            self.pending.push_back(frame);
+        }
+        self.frames = FrameBuffer::new();
 
-        let child = Command::new("zwgc")
+        let child = Command::new(&self.zwgc)
             .arg("-nofork")
             .arg("-ttymode")
             .arg("-f")
-            .arg(format!("{}", self.format_file.as_ref().unwrap().path().to_str().unwrap()))
+            .arg(self.format_file.as_ref().ok_or_else(closed)?.path())
             .arg("-subfile")
-            .arg(format!("{}", self.sub_file.as_ref().unwrap().path().to_str().unwrap()))
+            .arg(self.sub_file.as_ref().ok_or_else(closed)?.path())
+            // zwgc records its port here, so that zctl can find it
+            .env("WGFILE", self.wg_file.as_ref().ok_or_else(closed)?.path())
             .stdout(Stdio::piped())
             .spawn()?;
 
@@ -203,84 +345,156 @@ impl Zephyr {
         Ok(())
     }
 
+    /// Subscribes the running zwgc to a triplet, and records it so
+    /// that it survives restarts. Nothing changes if zctl can't reach
+    /// zwgc
+    pub fn subscribe(&mut self, triplet: Triplet) -> Result<()> {
+        if self.subs.contains(&triplet) {
+            return Ok(())
+        }
+        self.zctl("subscribe", &triplet)?;
+        self.subs.push(triplet);
+        self.write_subs()
+    }
+
+    /// Unsubscribes the running zwgc from a triplet
+    pub fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()> {
+        if !self.subs.contains(triplet) {
+            return Ok(())
+        }
+        self.zctl("unsubscribe", triplet)?;
+        self.subs.retain(|t| t != triplet);
+        self.write_subs()
+    }
+
+    fn zctl(&mut self, action: &str, triplet: &Triplet) -> Result<()> {
+        let status = Command::new("zctl")
+            .arg(action)
+            .arg(&triplet.class)
+            .arg(triplet.instance.as_ref().map(|x| x.as_ref()).unwrap_or("*"))
+            .arg(triplet.recipient.as_ref().map(|x| x.as_ref()).unwrap_or("*"))
+            .env("WGFILE", self.wg_file.as_ref().ok_or_else(closed)?.path())
+            .stdout(Stdio::null())
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(ZError::Rejected(format!("zctl {} {} failed: {}", action, triplet, status)))
+        }
+    }
+
     pub fn kill(&mut self) -> Result<()> {
-        if let Some(ref mut child) = self.child.as_mut() {
-            child.kill()?;
+        if let Some(mut child) = self.child.take() {
+            // zwgc may already have died on its own
+            if child.try_wait()?.is_none() {
+                child.kill()?;
+            }
+            child.wait()?;
         }
-        self.child = None;
         Ok(())
     }
 
-    pub fn read_raw(&mut self) -> Result<String> {
-        let child = self.child.as_mut().unwrap();
-        let out = child.stdout.as_mut().unwrap();
+    /// Stops zwgc and removes the temporary files. The connection
+    /// can't be used afterwards
+    pub fn close(&mut self) -> Result<()> {
+        let mut result = self.kill();
 
-        let mut bytes = vec![];
-        let mut buffer = [0; 512];
+        for file in [self.format_file.take(), self.sub_file.take()].iter_mut() {
+            if let Some(Err(e)) = file.take().map(|f| f.close()) {
+                if result.is_ok() {
+                    result = Err(e.into());
+                }
+            }
+        }
+
+        // zwgc removes this itself when it exits cleanly
+        if let Some(wg_file) = self.wg_file.take() {
+            let _ = wg_file.close();
+        }
 
-        let mut reader = BufReader::new(out);
+        result
+    }
 
+    /// Reads the next complete notice printed by zwgc, as raw text
+    pub fn read_raw(&mut self) -> Result<String> {
         loop {
-            let len = reader.read(&mut buffer)?;
-            bytes.extend_from_slice(&buffer[..len]);
-            if len < buffer.len() {
-                break;
+            if let Some(frame) = self.read_raw_with(None)? {
+                return Ok(frame)
             }
         }
+    }
 
-        Ok(String::from_utf8(bytes).unwrap())
+    /// Like `read_raw`, but gives up once `timeout` has passed
+    /// without a complete notice
+    pub fn read_raw_timeout(&mut self, timeout: Duration) -> Result<Option<String>> {
+        self.read_raw_with(Some(timeout))
     }
 
-    pub fn read(&mut self) -> Result<Notice> {
-        let raw = self.read_raw()?;
+    fn read_raw_with(&mut self, timeout: Option<Duration>) -> Result<Option<String>> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(Some(frame))
+        }
 
-        let mut opcode   = String::new();
-        let mut class    = String::new();
-        let mut instance = String::new();
-        let mut sender   = String::new();
-        let mut auth     = String::new();
-        let mut time     = String::new();
-        let mut date     = String::new();
-        let mut host     = String::new();
-        let mut zsig     = String::new();
-        let mut body     = Vec::new();
-
-        for line in raw.split('\n') {
-            let split = line.splitn(2, ": ").collect::<Vec<_>>();
-            match split[0] {
-                "opcode"    => opcode   += split[1],
-                "class"     => class    += split[1],
-                "instance"  => instance += split[1],
-                "sender"    => sender   += split[1],
-                "auth"      => auth     += split[1],
-                "time"      => time     += split[1],
-                "date"      => date     += split[1],
-                "fromhost"  => host     += split[1],
-                "signature" => zsig     += split[1],
-                "body"      => body.push(split[1].to_string()),
-                _ => {}
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match self.next_frame(remaining)? {
+                // a zwgc which restarted itself
+                Some(ref frame) if frame == READY => continue,
+                frame => return Ok(frame),
             }
         }
+    }
 
-        let incoming_data = Some(IncomingData {
-            is_auth: auth == "yes",
-            date: Duration::from_millis(0), // FIXME
-            host,
-        });
+    /// The next frame printed by zwgc, waiting at most `timeout`
+    /// for it, or forever if `None`
+    fn next_frame(&mut self, timeout: Option<Duration>) -> Result<Option<String>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(frame) = self.frames.next_frame() {
+                return Ok(Some(frame))
+            }
 
-        let notice = Notice {
-            opcode,
-            direction: Direction::Incoming,
-            class,
-            instance,
-            sender,
-            zsig,
-            body,
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let fd = self.stdout()?.as_raw_fd();
+                if !wait_readable(&[fd], Some(remaining))? {
+                    return Ok(None)
+                }
+            }
+            self.fill()?;
+        }
+    }
 
-            incoming_data,
-        };
+    pub fn read(&mut self) -> Result<Notice> {
+        let raw = self.read_raw()?;
+        Ok(parse_frame(&raw)?)
+    }
+
+    pub fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Notice>> {
+        match self.read_raw_timeout(timeout)? {
+            Some(raw) => Ok(Some(parse_frame(&raw)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(notice)
+    fn stdout(&mut self) -> Result<&mut ChildStdout> {
+        let child = self.child.as_mut()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "zwgc is not running"))?;
+        Ok(child.stdout.as_mut().unwrap())
+    }
+
+    /// Reads whatever zwgc has printed into the frame buffer, blocking
+    /// until it prints something
+    fn fill(&mut self) -> Result<()> {
+        let mut buffer = [0; 512];
+        let len = self.stdout()?.read(&mut buffer)?;
+        if len == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "zwgc closed its output").into())
+        }
+        self.frames.extend(&buffer[..len]);
+        Ok(())
     }
 
     // NB: self is &mut for future-proofing
@@ -291,38 +505,293 @@ impl Zephyr {
             body += format!("{}\n", line).as_str();
         }
 
-        let mut child = Command::new("zwrite")
+        let mut zwrite = Command::new("zwrite");
+        zwrite
             .arg("-d")
             .arg("-c").arg(notice.class.as_str())
             .arg("-i").arg(notice.instance.as_str())
             .arg("-S").arg(notice.sender.as_str())
             .arg("-s").arg(notice.zsig.as_str())
-            .arg("-O").arg(notice.opcode.as_str())
-            .arg("-m").arg(body)
-            .spawn()?;
+            .arg("-O").arg(notice.opcode.as_str());
+
+        if !notice.recipient.is_empty() {
+            zwrite.arg(notice.recipient.as_str());
+        }
 
-        child.wait()?;
+        let status = zwrite.arg("-m").arg(body).status()?;
+        if !status.success() {
+            return Err(ZError::Rejected(format!("zwrite failed: {}", status)))
+        }
 
         Ok(())
     }
 
 }
 
+impl Transport for Zephyr {
+
+    fn read(&mut self) -> Result<Notice> {
+        Zephyr::read(self)
+    }
+
+    fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Notice>> {
+        Zephyr::read_timeout(self, timeout)
+    }
+
+    fn send(&mut self, notice: &Notice) -> Result<()> {
+        self.zwrite(notice)
+    }
+
+    fn subscribe(&mut self, triplet: Triplet) -> Result<()> {
+        Zephyr::subscribe(self, triplet)
+    }
+
+    fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()> {
+        Zephyr::unsubscribe(self, triplet)
+    }
+
+    fn subs(&self) -> &Vec<Triplet> {
+        &self.subs
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        self.child.as_ref()
+            .and_then(|child| child.stdout.as_ref())
+            .map(|out| out.as_raw_fd())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Zephyr::close(self)
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        // the subscription file is always up to date, so a fresh zwgc
+        // picks up every subscription
+        self.connect()
+    }
+}
+
 impl Drop for Zephyr {
     fn drop(&mut self) {
-        self.kill().expect("failed to destroy process");
+        if let Err(e) = self.close() {
+            eprintln!("failed to clean up: {}", e);
+        }
+    }
+}
+
+/// How long zwgc may take to start
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The frame `FORMAT` prints for zwgc's own startup notice
+const READY: &str = "ready\n";
+
+fn closed() -> ZError {
+    io::Error::new(ErrorKind::NotConnected, "zephyr connection was closed").into()
+}
+
+/// Splits zwgc output into notices on the `done` sentinel printed
+/// by `FORMAT`, keeping any partial notice for the next read
+#[derive(Default)]
+pub struct FrameBuffer {
+    bytes: Vec<u8>,
+}
+
+impl FrameBuffer {
+
+    pub fn new() -> FrameBuffer {
+        FrameBuffer { bytes: vec![] }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Removes and returns the next complete frame, without its
+    /// sentinel. Invalid UTF-8 is replaced rather than rejected
+    pub fn next_frame(&mut self) -> Option<String> {
+        const SENTINEL: &[u8] = b"done\n";
+
+        let mut start = 0;
+        while start + SENTINEL.len() <= self.bytes.len() {
+            if &self.bytes[start..start + SENTINEL.len()] == SENTINEL {
+                let frame = String::from_utf8_lossy(&self.bytes[..start]).into_owned();
+                self.bytes.drain(..start + SENTINEL.len());
+                return Some(frame)
+            }
 
-        let mut format_file = None;
-        let mut sub_file = None;
+            // the sentinel always starts a line
+            match self.bytes[start..].iter().position(|&b| b == b'\n') {
+                Some(end) => start += end + 1,
+                None => break,
+            }
+        }
+        None
+    }
+}
 
-        mem::swap(&mut self.format_file, &mut format_file);
-        mem::swap(&mut self.sub_file, &mut sub_file);
+/// Error produced when a frame printed by zwgc is not a valid notice
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// A line which is neither a field nor a flag
+    MalformedLine(String),
+    /// A field every notice must have was not printed
+    MissingField(&'static str),
+}
 
-        format_file.unwrap().close().expect("failed to destroy temp file");
-        sub_file.unwrap().close().expect("failed to destroy temp file");
+impl Display for FrameError {
+
+    fn fmt(&self, f: &mut Formatter) -> SResult<(), Error> {
+        match *self {
+            FrameError::MalformedLine(ref line) => write!(f, "malformed zwgc line {:?}", line),
+            FrameError::MissingField(name) => write!(f, "zwgc output is missing the {} field", name),
+        }
     }
 }
 
+impl error::Error for FrameError {}
+
+/// Parses a single frame of zwgc output into a notice
+pub fn parse_frame(frame: &str) -> SResult<Notice, FrameError> {
+    let mut opcode   = String::new();
+    let mut class    = None;
+    let mut instance = String::new();
+    let mut sender   = None;
+    let mut recipient = String::new();
+    let mut personal = false;
+    let mut kind     = String::new();
+    let mut port     = String::new();
+    let mut auth     = String::new();
+    let mut time     = String::new();
+    let mut date     = String::new();
+    let mut host     = String::new();
+    let mut zsig     = String::new();
+    let mut body     = Vec::new();
+
+    for line in frame.split('\n') {
+        let (key, value) = match line.find(':') {
+            Some(i) => {
+                let value = &line[i + 1..];
+                (&line[..i], value.strip_prefix(' ').unwrap_or(value))
+            },
+            None => match line.trim() {
+                "" => continue,
+                "personal" => {
+                    personal = true;
+                    continue
+                },
+                _ => return Err(FrameError::MalformedLine(line.to_string())),
+            },
+        };
+
+        match key {
+            "opcode"    => opcode   += value,
+            "class"     => *class.get_or_insert_with(String::new) += value,
+            "instance"  => instance += value,
+            "sender"    => *sender.get_or_insert_with(String::new) += value,
+            "recipient" => recipient += value,
+            "kind"      => kind     += value,
+            "port"      => port     += value,
+            "auth"      => auth     += value,
+            "time"      => time     += value,
+            "date"      => date     += value,
+            "fromhost"  => host     += value,
+            "signature" => zsig     += value,
+            "body"      => body.push(value.to_string()),
+            _ => {}
+        }
+    }
+
+    let sender = sender.ok_or(FrameError::MissingField("sender"))?;
+
+    // zwgc prints "*" for notices without a recipient
+    if recipient == "*" {
+        recipient.clear();
+    }
+
+    let incoming_data = Some(IncomingData {
+        is_auth: auth == "yes",
+        is_personal: personal,
+        date: parse_date(&time, &date),
+        host,
+        realm: realm_of(&recipient, &sender),
+        port: port.parse().ok(),
+        kind: Kind::from_name(&kind),
+        uid: None,
+    });
+
+    Ok(Notice {
+        opcode,
+        direction: Direction::Incoming,
+        class: class.ok_or(FrameError::MissingField("class"))?,
+        instance,
+        sender,
+        recipient,
+        zsig,
+        body,
+
+        incoming_data,
+    })
+}
+
+/// Whether a notice sent to `recipient` is personal. Recipients of
+/// the form "@REALM" are broadcasts to another realm
+pub fn is_personal_recipient(recipient: &str) -> bool {
+    !recipient.is_empty() && !recipient.starts_with('@')
+}
+
+/// The realm a notice belongs to: that of its recipient, or of its
+/// sender for broadcasts within the sender's realm
+pub fn realm_of(recipient: &str, sender: &str) -> String {
+    let principal = if recipient.contains('@') { recipient } else { sender };
+    principal.rsplit_once('@').map(|(_, realm)| realm.to_string()).unwrap_or_default()
+}
+
+/// Converts zwgc's `$time` ("21:49:08") and `$date` ("Wed Jun 30 1993")
+/// fields, which are in the local timezone, into a timestamp
+fn parse_date(time: &str, date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun",
+        "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let mut hms = time.split_whitespace().next()?.split(':').map(|x| x.parse::<i32>().ok());
+    let hour = hms.next()??;
+    let min = hms.next()??;
+    let sec = hms.next()??;
+
+    let mut month = None;
+    let mut day = None;
+    let mut year = None;
+    for word in date.split_whitespace() {
+        let lower = word.to_lowercase();
+        if let Some(i) = MONTHS.iter().position(|m| lower.starts_with(m)) {
+            month = Some(i as i32);
+        } else if let Ok(n) = word.parse::<i32>() {
+            if word.len() == 4 {
+                year = Some(n);
+            } else if (1..=31).contains(&n) {
+                day = Some(n);
+            }
+        }
+    }
+
+    // mktime wants local time, and works out DST for us given tm_isdst = -1
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    tm.tm_sec = sec;
+    tm.tm_min = min;
+    tm.tm_hour = hour;
+    tm.tm_mday = day?;
+    tm.tm_mon = month?;
+    tm.tm_year = year? - 1900;
+    tm.tm_isdst = -1;
+
+    let secs = unsafe { libc::mktime(&mut tm) };
+    if secs < 0 {
+        return None
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
 fn wrap_lines(limit: usize, val: &str) -> Vec<String> {
     lazy_static! {
         static ref PATTERN: Regex = Regex::new("[ \0]").unwrap();
@@ -350,7 +819,7 @@ fn wrap_lines(limit: usize, val: &str) -> Vec<String> {
         }
     }
 
-    buf.split("\n").map(|x| x.to_string()).collect::<Vec<_>>()
+    buf.split('\n').map(|x| x.to_string()).collect::<Vec<_>>()
 }
 
 // ZWGC format file
@@ -364,6 +833,12 @@ case downcase($class)
 match "mail"
 	exit
 
+match "wg_ctl_class"
+	print "ready\n"
+	print "done\n"
+	put "stdout"
+	exit
+
 default
 	fields signature body
 	if (downcase($recipient) == downcase($user)) then
@@ -389,6 +864,21 @@ default
 		print "\n"
 		set dummy = lany($sender, "\n")
 	endwhile
+	while ($recipient != "") do
+		print "recipient:" lbreak($recipient, "\n")
+		print "\n"
+		set dummy = lany($recipient, "\n")
+	endwhile
+	while ($kind != "") do
+		print "kind:" lbreak($kind, "\n")
+		print "\n"
+		set dummy = lany($kind, "\n")
+	endwhile
+	while ($port != "") do
+		print "port:" lbreak($port, "\n")
+		print "\n"
+		set dummy = lany($port, "\n")
+	endwhile
 	while ($auth != "") do
 		print "auth:" lbreak($auth, "\n")
 		print "\n"
@@ -416,7 +906,7 @@ default
 	endwhile
 	while ($body != "") do
 		print "body:" lbreak($body, "\n")
-		print " \n"
+		print "\n"
 		set dummy = lany($body, "\n")
 	endwhile
 	print "done\n"
@@ -0,0 +1,160 @@
+#![cfg(feature = "async")]
+
+extern crate futures;
+extern crate zpet;
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::{FutureExt, StreamExt};
+
+use zpet::*;
+use zpet::asynchronous::Notices;
+use zpet::error::Result;
+use zpet::mock::{self, MockZephyr};
+use zpet::transport::Transport;
+
+#[test]
+fn slow_commands_do_not_stall_others() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command_async(Shape::order(), Scope::Local, vec!["fetch"], |state, notice, _| {
+            let handle = state.handle();
+            let notice = notice.clone();
+
+            let (tx, rx) = oneshot::channel();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let _ = tx.send("*brings the ball back*");
+            });
+            rx.map(move |body| {
+                handle.reply_to(&notice, body.unwrap()).unwrap();
+            })
+        })
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .build().unwrap();
+
+    zio.push(mock::incoming("topy", "home", "alice", "topy, fetch!"));
+    zio.push(mock::incoming("topy", "home", "bob", "topy, sit!"));
+    bot.run_once().unwrap();
+
+    assert_eq!(bot.state.spawned(), 1);
+    assert_eq!(zio.sent().len(), 1);
+    assert_eq!(zio.sent()[0].body, vec!["*sits*"]);
+
+    for _ in 0..100 {
+        if zio.sent().len() == 2 {
+            break
+        }
+        bot.run_once().unwrap();
+    }
+
+    let sent = zio.sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[1].body, vec!["*brings the ball back*"]);
+    assert_eq!(bot.state.spawned(), 0);
+}
+
+#[test]
+fn async_handlers_can_pass_notices_on() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .pre_async(|state, notice| {
+            if notice.sender != "alice" {
+                return None
+            }
+            let handle = state.handle();
+            let notice = notice.clone();
+            Some(futures::future::lazy(move |_| {
+                handle.reply_to(&notice, "*wags*").unwrap();
+            }))
+        })
+        .post(|state, notice| {
+            state.reply_to(notice, "*ignores*").unwrap();
+            true
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "hi"));
+    bot.tick(mock::incoming("topy", "home", "bob", "hi"));
+    bot.run_once().unwrap();
+    bot.run_once().unwrap();
+
+    let bodies = zio.sent().into_iter().map(|n| n.body[0].clone()).collect::<Vec<_>>();
+    assert_eq!(bodies, vec!["*ignores*", "*wags*"]);
+}
+
+#[test]
+fn transports_are_streams_of_notices() {
+    let zio = MockZephyr::new(vec![]);
+    zio.push(mock::incoming("topy", "home", "alice", "one"));
+    zio.push(mock::incoming("topy", "home", "alice", "two"));
+
+    let notices = block_on(Notices::new(zio.clone()).take(2).collect::<Vec<_>>());
+    let bodies = notices.into_iter().map(|n| n.unwrap().body[0].clone()).collect::<Vec<_>>();
+    assert_eq!(bodies, vec!["one", "two"]);
+}
+
+/// A transport with nothing to wait on, fed from another thread
+struct Channel {
+    notices: Receiver<Notice>,
+    subs: Vec<Triplet>,
+}
+
+impl Transport for Channel {
+
+    fn read(&mut self) -> Result<Notice> {
+        Ok(self.notices.recv().unwrap())
+    }
+
+    fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Notice>> {
+        match self.notices.recv_timeout(timeout) {
+            Ok(notice) => Ok(Some(notice)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => panic!("sender hung up"),
+        }
+    }
+
+    fn send(&mut self, _notice: &Notice) -> Result<()> {
+        Ok(())
+    }
+
+    fn subscribe(&mut self, triplet: Triplet) -> Result<()> {
+        self.subs.push(triplet);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, triplet: &Triplet) -> Result<()> {
+        self.subs.retain(|t| t != triplet);
+        Ok(())
+    }
+
+    fn subs(&self) -> &Vec<Triplet> {
+        &self.subs
+    }
+}
+
+#[test]
+fn waiting_for_notices_does_not_block() {
+    let (tx, rx) = mpsc::channel();
+    let mut notices = Notices::new(Channel { notices: rx, subs: vec![] });
+
+    // nothing has been sent yet, so polling must return straight away
+    assert!(notices.next().now_or_never().is_none());
+
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        tx.send(mock::incoming("topy", "home", "alice", "hi")).unwrap();
+    });
+    let notice = block_on(notices.next()).unwrap().unwrap();
+    assert_eq!(notice.body, vec!["hi"]);
+}
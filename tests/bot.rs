@@ -0,0 +1,744 @@
+extern crate libc;
+extern crate zpet;
+
+mod common;
+
+use zpet::*;
+use zpet::mock::{self, MockZephyr};
+
+use common::pet_bot;
+
+#[test]
+fn replies_to_commands_in_scope() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!"));
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].class, "topy");
+    assert_eq!(sent[0].instance, "home");
+    assert_eq!(sent[0].sender, "topy");
+    assert_eq!(sent[0].zsig, "woof");
+    assert_eq!(sent[0].body, vec!["*sits*"]);
+}
+
+#[test]
+fn ignores_local_commands_elsewhere() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    bot.tick(mock::incoming("topy", "kitchen", "alice", "topy, sit!"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy, sit!"));
+
+    assert!(zio.sent().is_empty());
+}
+
+#[test]
+fn ignores_unsubscribed_and_auto_notices() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    bot.tick(mock::incoming("elsewhere", "home", "alice", "topy.go(games)"));
+
+    let mut auto = mock::incoming("topy", "home", "alice", "topy, sit!");
+    auto.opcode = "AUTO".to_string();
+    bot.tick(auto);
+
+    assert!(zio.sent().is_empty());
+}
+
+#[test]
+fn move_to_changes_local_scope() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy.go(games)"));
+    assert_eq!(bot.state.location(), Triplet::of_instance("games", "home"));
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy, sit!"));
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].body, vec!["*arrives*"]);
+    assert_eq!(sent[1].class, "games");
+    assert_eq!(sent[1].body, vec!["*sits*"]);
+}
+
+#[test]
+fn step_reads_scripted_notices() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    zio.push(mock::incoming("topy", "home", "alice", "sit, topy!"));
+    assert_eq!(zio.pending(), 1);
+
+    bot.step().unwrap();
+    assert_eq!(zio.pending(), 0);
+    assert_eq!(zio.sent().len(), 1);
+
+    assert!(bot.step().is_err());
+}
+
+#[test]
+fn handlers_run_around_commands() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .pre(|state, notice| {
+            if notice.sender == "mallory" {
+                state.reply_to(notice, "*growls*").unwrap();
+                return true
+            }
+            false
+        })
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .post(|state, notice| {
+            state.reply_to(notice, "*tilts head*").unwrap();
+            true
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "mallory", "topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "hello"));
+
+    let bodies = zio.sent().into_iter().map(|n| n.body.join("\n")).collect::<Vec<_>>();
+    assert_eq!(bodies, vec!["*growls*", "*sits*", "*tilts head*"]);
+}
+
+#[test]
+fn personal_scope_only_answers_personals() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .sub_to_personals()
+        .command(Shape::order(), Scope::Personal, vec!["secret"], |state, _, _| {
+            state.reply_here("*whispers*").unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, secret!"));
+    assert!(zio.sent().is_empty());
+
+    bot.tick(mock::personal("alice", "topy", "topy, secret!"));
+    assert_eq!(zio.sent().len(), 1);
+}
+
+#[test]
+fn replies_to_personals_privately() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .sub_to_personals()
+        .command(Shape::order(), Scope::Everywhere, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .command(Shape::unary_order(), Scope::Everywhere, vec!["whisper"], |state, _, cm| {
+            state.reply_personal(cm.args[0], "*licks ear*").unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::personal("alice", "topy", "topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, whisper bob"));
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 3);
+    assert_eq!((&sent[0].class[..], &sent[0].recipient[..]), ("MESSAGE", "alice"));
+    assert!(sent[0].is_personal());
+    assert_eq!((&sent[1].class[..], &sent[1].recipient[..]), ("topy", ""));
+    assert_eq!((&sent[2].instance[..], &sent[2].recipient[..]), ("PERSONAL", "bob"));
+}
+
+#[test]
+fn subscribes_at_runtime() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command(Shape::unary_invoke(), Scope::Everywhere, vec!["join"], |state, notice, cm| {
+            state.subscribe(Triplet::of_class(cm.args[0])).unwrap();
+            state.reply_to(notice, "*wags tail*").unwrap();
+        })
+        .command(Shape::unary_invoke(), Scope::Everywhere, vec!["leave"], |state, _, cm| {
+            state.unsubscribe(&Triplet::of_class(cm.args[0])).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("games", "home", "alice", "topy.join(games)"));
+    assert!(zio.take_sent().is_empty());
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy.join(games)"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy.join(games)"));
+    assert_eq!(bot.state.subs().len(), 2);
+
+    bot.tick(mock::incoming("games", "home", "alice", "topy.join(games)"));
+    assert_eq!(zio.take_sent().len(), 3);
+
+    bot.tick(mock::incoming("games", "home", "alice", "topy.leave(games)"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy.join(games)"));
+    assert!(zio.take_sent().is_empty());
+    assert_eq!(*bot.state.subs(), vec![Triplet::of_class("topy")]);
+}
+
+#[test]
+fn reconnects_after_losing_connection() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let seen = events.clone();
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .on_event(move |_, event| seen.borrow_mut().push(event))
+        .build().unwrap();
+
+    zio.push(mock::incoming("topy", "home", "alice", "hi"));
+    zio.disconnect();
+
+    let err = bot.step().unwrap_err();
+    assert!(err.is_disconnect());
+
+    bot.reconnect();
+    assert_eq!(zio.reconnects(), 1);
+    assert_eq!(*events.borrow(), vec![Event::Disconnected, Event::Reconnected]);
+
+    bot.step().unwrap();
+    assert_eq!(zio.pending(), 0);
+}
+
+#[test]
+fn waits_before_reconnecting_an_unhealthy_connection() {
+    use std::time::{Duration, Instant};
+
+    let delay = Duration::from_millis(100);
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .reconnect_backoff(delay, delay * 10)
+        .build().unwrap();
+
+    bot.reconnect();
+    let start = Instant::now();
+    bot.reconnect();
+    assert!(start.elapsed() >= delay);
+    assert_eq!(zio.reconnects(), 2);
+
+    zio.push(mock::incoming("topy", "home", "alice", "hi"));
+    bot.step().unwrap();
+    let start = Instant::now();
+    bot.reconnect();
+    assert!(start.elapsed() < delay);
+    assert_eq!(zio.reconnects(), 3);
+}
+
+#[test]
+fn backoff_doubles_up_to_max() {
+    use std::time::Duration;
+    use zpet::transport::Backoff;
+
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+    let delays = (0..5).map(|_| backoff.next_delay().as_secs()).collect::<Vec<_>>();
+    assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+}
+
+#[test]
+fn extra_state_is_shared_between_commands() {
+    struct Pet {
+        treats: u32,
+    }
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .with_extra(Pet { treats: 0 })
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command(Shape::order(), Scope::Local, vec!["treat"], |state, _, _| {
+            state.extra_mut().treats += 1;
+        })
+        .command(Shape::order(), Scope::Local, vec!["count"], |state, notice, _| {
+            let body = format!("*has had {} treats*", state.extra_ref().treats);
+            state.reply_to(notice, &body).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, treat!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, treat!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, count!"));
+
+    assert_eq!(zio.sent()[0].body, vec!["*has had 2 treats*"]);
+}
+
+#[test]
+fn runs_scheduled_tasks() {
+    use std::thread;
+    use std::time::Duration;
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .every(Duration::from_millis(10), |state| {
+            state.reply_here("*wags*").unwrap();
+        })
+        .command(Shape::order(), Scope::Local, vec!["stay"], |state, _, _| {
+            state.schedule_after(Duration::from_millis(0), |state| {
+                state.reply_here("*stays*").unwrap();
+            });
+        })
+        .build().unwrap();
+
+    bot.run_timers();
+    assert!(zio.sent().is_empty());
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, stay!"));
+    assert_eq!(bot.state.scheduled(), 2);
+    bot.run_timers();
+    assert_eq!(zio.take_sent()[0].body, vec!["*stays*"]);
+    assert_eq!(bot.state.scheduled(), 1);
+
+    thread::sleep(Duration::from_millis(15));
+    bot.run_timers();
+    thread::sleep(Duration::from_millis(15));
+    bot.run_timers();
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert!(sent.iter().all(|n| n.body == vec!["*wags*"]));
+    assert_eq!(bot.state.scheduled(), 1);
+}
+
+#[test]
+fn step_timeout_returns_without_notices() {
+    use std::time::Duration;
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    zio.push(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    assert!(bot.step_timeout(Duration::from_millis(10)).unwrap());
+    assert!(!bot.step_timeout(Duration::from_millis(10)).unwrap());
+    assert_eq!(zio.sent().len(), 1);
+}
+
+#[test]
+fn invalid_cron_fails_build() {
+    let zio = MockZephyr::new(vec![]);
+    let result = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .at("61 * * * *", |_| {})
+        .build();
+
+    match result {
+        Err(Error::Cron(e)) => assert_eq!(e.expr, "61 * * * *"),
+        _ => panic!("expected a cron error"),
+    }
+}
+
+#[test]
+fn empty_zsigs_fail_build() {
+    let zio = MockZephyr::new(vec![]);
+    let result = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .with_zsigs(vec![])
+        .build();
+
+    match result {
+        Err(Error::Config(_)) => {},
+        _ => panic!("expected a config error"),
+    }
+}
+
+#[test]
+fn handles_requests_from_other_threads() {
+    use std::thread;
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+    let handle = bot.handle();
+
+    thread::spawn(move || {
+        handle.inject(mock::incoming("topy", "home", "alice", "topy, sit!")).unwrap();
+        handle.reply_at(&Triplet::of_instance("games", "lobby"), "*bounces*").unwrap();
+    }).join().unwrap();
+
+    bot.run_once().unwrap();
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].body, vec!["*sits*"]);
+    assert_eq!(sent[1].class, "games");
+    assert_eq!(sent[1].instance, "lobby");
+    assert_eq!(sent[1].zsig, "woof");
+}
+
+#[test]
+fn run_once_handles_every_ready_notice() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio);
+
+    for _ in 0..3 {
+        zio.push(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    }
+    bot.run_once().unwrap();
+
+    assert_eq!(zio.pending(), 0);
+    assert_eq!(zio.sent().len(), 3);
+}
+
+#[test]
+fn handle_fails_once_the_bot_is_gone() {
+    let zio = MockZephyr::new(vec![]);
+    let handle = pet_bot(&zio).handle();
+
+    assert!(handle.reply_at(&Triplet::of_class("topy"), "hello?").is_err());
+}
+
+#[test]
+fn shuts_down_from_commands() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .catch_signals(false)
+        .command(Shape::order(), Scope::Local, vec!["sleep"], |state, _, _| {
+            state.shutdown();
+        })
+        .on_shutdown(|state| {
+            state.reply_here("*curls up*").unwrap();
+        })
+        .build().unwrap();
+
+    zio.push(mock::incoming("topy", "home", "alice", "topy, sleep!"));
+    zio.push(mock::incoming("topy", "home", "alice", "topy, sleep!"));
+    bot.run().unwrap();
+
+    // the second notice is never read
+    assert_eq!(zio.pending(), 1);
+    assert_eq!(zio.sent()[0].body, vec!["*curls up*"]);
+    assert!(zio.is_closed());
+}
+
+#[test]
+fn shuts_down_from_handles() {
+    use std::thread;
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .catch_signals(false)
+        .build().unwrap();
+
+    let handle = bot.handle();
+    thread::spawn(move || handle.shutdown().unwrap());
+    bot.run().unwrap();
+
+    assert!(zio.is_closed());
+}
+
+#[test]
+fn shuts_down_on_sigterm() {
+    use std::time::Duration;
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .every(Duration::from_millis(1), |_| {
+            unsafe { libc::raise(libc::SIGTERM) };
+        })
+        .on_event(|state, event| {
+            if event == Event::Shutdown {
+                state.reply_here("*yawns*").unwrap();
+            }
+        })
+        .build().unwrap();
+
+    bot.run().unwrap();
+
+    assert_eq!(zio.sent()[0].body, vec!["*yawns*"]);
+    assert!(zio.is_closed());
+}
+
+#[test]
+fn typed_commands_explain_bad_arguments() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command_typed(Shape::unary_order(), Scope::Local, vec!["fetch"], |state, notice, (count,): (u32,)| {
+            state.reply_to(notice, &format!("*fetches {} balls*", count)).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, fetch 3"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, fetch lots"));
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].body, vec!["*fetches 3 balls*"]);
+    assert_eq!(sent[1].recipient, "alice");
+    assert_eq!(sent[1].body[0], "argument 1 (\"lots\"): invalid digit found in string");
+    assert_eq!(sent[1].body[1], "usage: topy, fetch <arg>[.|!]");
+}
+
+#[test]
+fn answers_to_aliases() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .alias("tp")
+        .referent_ignore_case(true)
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, cm| {
+            state.reply_to(notice, &format!("*sits for {}*", cm.alias)).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "Topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "TP, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "toppy, sit!"));
+
+    let bodies = zio.take_sent().into_iter().map(|n| n.body[0].clone()).collect::<Vec<_>>();
+    assert_eq!(bodies, vec!["*sits for topy*", "*sits for tp*"]);
+}
+
+#[test]
+fn help_lists_commands_in_scope() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_classes(vec!["topy", "games"])
+        .help()
+        .command(Shape::order(), Scope::Local, vec!["sit"], |_, _, _| {})
+            .describe("Sits down")
+        .command(Shape::unary_invoke(), Scope::Everywhere, vec!["fetch", "get"], |_, _, _| {})
+            .describe("Fetches something")
+            .example("topy.fetch(ball)")
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, help!"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy.help()"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy.help(get)"));
+    bot.tick(mock::incoming("games", "home", "alice", "topy, help sit"));
+
+    let sent = zio.take_sent();
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[0].body, vec![
+        "sit: topy, sit[.|!] - Sits down",
+        "fetch: topy->fetch[(<arg>)] - Fetches something",
+        "Say \"topy, help <command>\" for details",
+    ]);
+    assert_eq!(sent[1].body.len(), 2);
+    assert_eq!(sent[2].body[0], "fetch: Fetches something");
+    assert_eq!(sent[2].body[1], "also: get");
+    assert_eq!(sent[2].body[2], "usage: topy->fetch[(<arg>)]");
+    assert_eq!(sent[2].body.last().unwrap(), "e.g. topy.fetch(ball)");
+    assert_eq!(sent[3].body, vec!["I don't know a command called \"sit\""]);
+}
+
+#[test]
+fn describes_only_the_last_command() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .help()
+        .command(Shape::order(), Scope::Local, vec!["sit"], |_, _, _| {})
+        .pre(|_, _| false)
+            .describe("Sits down")
+            .example("topy, sit!")
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, help sit"));
+    let body = zio.take_sent()[0].body.clone();
+    assert_eq!(body[0], "sit");
+    assert!(!body.iter().any(|line| line.starts_with("e.g.")));
+}
+
+#[test]
+fn dispatches_by_priority() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .command(Shape::order(), Scope::Local, vec!["sit", "stay"], |state, notice, _| {
+            state.reply_to(notice, "*wags*").unwrap();
+            Dispatch::Continue
+        })
+            .priority(1)
+        .pre(|_, notice| if notice.sender == "mallory" { Dispatch::Stop } else { Dispatch::Continue })
+        .post(|state, notice| {
+            state.reply_to(notice, "*tilts head*").unwrap();
+            true
+        })
+        .build().unwrap();
+
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!")), Dispatch::Handled);
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "alice", "topy, stay!")), Dispatch::Handled);
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "mallory", "topy, sit!")), Dispatch::Stop);
+
+    let bodies = zio.take_sent().into_iter().map(|n| n.body[0].clone()).collect::<Vec<_>>();
+    assert_eq!(bodies, vec!["*wags*", "*sits*", "*wags*", "*tilts head*"]);
+}
+
+#[test]
+fn layers_wrap_dispatch() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let log = Rc::new(RefCell::new(vec![]));
+    let (outer, inner) = (log.clone(), log.clone());
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .wrap(move |state, notice, next| {
+            outer.borrow_mut().push("outer");
+            let result = next(state, notice);
+            outer.borrow_mut().push("outer done");
+            result
+        })
+        .wrap(move |state, notice, next| {
+            inner.borrow_mut().push("inner");
+            if notice.body[0].contains("cat") {
+                return Dispatch::Stop
+            }
+            next(state, notice)
+        })
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .build().unwrap();
+
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!")), Dispatch::Handled);
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "alice", "cat, sit!")), Dispatch::Stop);
+    assert_eq!(zio.take_sent().len(), 1);
+    assert_eq!(*log.borrow(), vec!["outer", "inner", "outer done", "outer", "inner", "outer done"]);
+}
+
+#[test]
+fn layers_see_extra_state() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .with_extra(0u32)
+        .wrap(|state, notice, next| {
+            *state.extra_mut() += 1;
+            next(state, notice)
+        })
+        .command(Shape::order(), Scope::Local, vec!["count"], |state, notice, _| {
+            let body = format!("*has been spoken to {} times*", state.extra_ref());
+            state.reply_to(notice, &body).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, count!"));
+    assert_eq!(zio.take_sent()[0].body, vec!["*has been spoken to 2 times*"]);
+}
+
+#[test]
+fn middleware_wraps_commands_and_handlers() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use zpet::bot::State;
+    use zpet::command::Middleware;
+
+    struct Log(Rc<RefCell<Vec<String>>>);
+
+    impl Middleware<()> for Log {
+        fn before(&self, _: &mut State<()>, notice: &Notice) -> Option<Dispatch> {
+            self.0.borrow_mut().push(format!("before {}", notice.sender));
+            None
+        }
+
+        fn after(&self, _: &mut State<()>, notice: &Notice, outcome: Dispatch) -> Dispatch {
+            self.0.borrow_mut().push(format!("after {} {:?}", notice.sender, outcome));
+            outcome
+        }
+    }
+
+    struct Banned(&'static str);
+
+    impl Middleware<()> for Banned {
+        fn before(&self, _: &mut State<()>, notice: &Notice) -> Option<Dispatch> {
+            if notice.sender == self.0 { Some(Dispatch::Stop) } else { None }
+        }
+    }
+
+    let log = Rc::new(RefCell::new(vec![]));
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .middleware(Log(log.clone()))
+        .middleware(Banned("mallory"))
+        .pre(|_, _| false)
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .command(Shape::order(), Scope::Local, vec!["stay"], |_, _, _| {})
+        .build().unwrap();
+
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "alice", "topy, sit!")), Dispatch::Handled);
+    assert_eq!(bot.tick(mock::incoming("topy", "home", "mallory", "topy, sit!")), Dispatch::Stop);
+
+    assert_eq!(zio.take_sent().len(), 1);
+    assert_eq!(*log.borrow(), vec![
+        "before alice", "after alice Continue",
+        "before alice", "after alice Handled",
+        "before mallory", "after mallory Stop",
+    ]);
+}
+
+#[test]
+fn middleware_sees_extra_state() {
+    use zpet::bot::State;
+    use zpet::command::Middleware;
+
+    struct Count;
+
+    impl Middleware<u32> for Count {
+        fn before(&self, state: &mut State<u32>, _: &Notice) -> Option<Dispatch> {
+            *state.extra_mut() += 1;
+            None
+        }
+    }
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .with_extra(0u32)
+        .middleware(Count)
+        .command(Shape::order(), Scope::Local, vec!["count"], |state, notice, _| {
+            let body = format!("*has been spoken to {} times*", state.extra_ref());
+            state.reply_to(notice, &body).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, count!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, count!"));
+    let sent = zio.take_sent();
+    assert_eq!(sent[1].body, vec!["*has been spoken to 2 times*"]);
+}
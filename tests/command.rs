@@ -0,0 +1,184 @@
+#[macro_use] extern crate zpet;
+
+use zpet::Shape;
+use zpet::command::{ArgError, CommandMatch, FromArgs, Lexicon, Referent};
+
+fn args<'a>(shape: &Shape, labels: Vec<&str>, val: &'a str) -> Option<Vec<&'a str>> {
+    shape.try_match("topy", &labels, val).map(|cm| cm.args)
+}
+
+#[test]
+fn order() {
+    let shape = Shape::order();
+    assert_eq!(args(&shape, vec!["sit"], "topy, sit!"), Some(vec![]));
+    assert_eq!(args(&shape, vec!["sit"], "sit , topy."), Some(vec![]));
+    assert_eq!(args(&shape, vec!["roll over"], "topy, roll over"), Some(vec![]));
+    assert_eq!(args(&shape, vec!["sit"], "rex, sit!"), None);
+    assert_eq!(args(&shape, vec!["sit"], "topy, stay!"), None);
+}
+
+#[test]
+fn unary_order() {
+    let shape = Shape::unary_order();
+    assert_eq!(args(&shape, vec!["get"], "topy, get ball!"), Some(vec!["ball"]));
+    assert_eq!(args(&shape, vec!["get"], "get ball, topy"), Some(vec!["ball"]));
+    assert_eq!(args(&shape, vec!["get"], "topy, get!"), None);
+}
+
+#[test]
+fn invoke() {
+    let shape = Shape::invoke();
+    for val in &["topy(pet)", "pets topy", "topy->{pet}", "topy.pet", "topy::pet()"] {
+        assert_eq!(args(&shape, vec!["pet"], val), Some(vec![]), "{}", val);
+    }
+    assert_eq!(args(&shape, vec!["pet"], "topy.feed"), None);
+}
+
+#[test]
+fn unary_invoke() {
+    let shape = Shape::unary_invoke();
+    assert_eq!(args(&shape, vec!["eat"], "topy.eat(kibble-2.0)"), Some(vec!["kibble-2.0"]));
+    assert_eq!(args(&shape, vec!["eat"], "topy#eat( 'a bone' )"), Some(vec!["a bone"]));
+    assert_eq!(args(&shape, vec!["eat"], "topy.eat"), Some(vec![]));
+}
+
+#[test]
+fn binary_invoke() {
+    let shape = Shape::binary_invoke();
+    assert_eq!(args(&shape, vec!["give"], "topy.give(a, b)"), Some(vec!["a", "b"]));
+    assert_eq!(args(&shape, vec!["give"], "topy->give('a b', 'c')"), Some(vec!["a b", "c"]));
+}
+
+#[test]
+fn do_with() {
+    let shape = Shape::do_with();
+    assert_eq!(args(&shape, vec!["pets"], "alice pets topy gently"), Some(vec!["gently"]));
+    assert_eq!(args(&shape, vec!["pets"], "alice pets topy with a brush."), Some(vec!["with a brush"]));
+}
+
+#[test]
+fn grammar() {
+    let shape = Shape::grammar(&["{self}, {cmd} {thing}[.|!]", "{self}.{cmd}({who:quoted}, {times:int})"]).unwrap();
+    assert_eq!(args(&shape, vec!["get"], "topy, get ball!"), Some(vec!["ball"]));
+    assert_eq!(args(&shape, vec!["get"], "topy,get ball"), Some(vec!["ball"]));
+    assert_eq!(args(&shape, vec!["pet"], "topy.pet('alice', 3)"), Some(vec!["alice", "3"]));
+    assert_eq!(args(&shape, vec!["pet"], "topy.pet( 'a b' ,-3 )"), Some(vec!["a b", "-3"]));
+    assert_eq!(args(&shape, vec!["pet"], "topy.pet('alice', lots)"), None);
+    assert_eq!(args(&shape, vec!["get"], "topy, getball"), None);
+}
+
+#[test]
+fn grammar_optional_and_variadic() {
+    let shape = Shape::grammar(&["{self}, {cmd} [{n:int} ]{items:word...}", "{self}: {cmd} {text:rest}"]).unwrap();
+    assert_eq!(args(&shape, vec!["buy"], "topy, buy 3 eggs, milk bread"), Some(vec!["3", "eggs", "milk", "bread"]));
+    assert_eq!(args(&shape, vec!["buy"], "topy, buy eggs"), Some(vec!["eggs"]));
+    assert_eq!(args(&shape, vec!["say"], "topy: say hi, all (twice)"), Some(vec!["hi, all (twice)"]));
+    assert_eq!(args(&shape, vec!["buy"], "topy, buy"), None);
+}
+
+#[test]
+fn grammar_errors() {
+    for template in &["{cmd} it", "{self} {self} {cmd}", "{self} {cmd} {x:float}",
+                      "{self} {cmd} [{x}", "{self} {cmd} {x...} {y}", "{self} {cmd} {x:rest...}",
+                      "[{self}] {cmd}", "{self} {cmd} a|b"] {
+        assert!(Shape::grammar(&[template]).is_err(), "{}", template);
+    }
+}
+
+struct Give {
+    what: String,
+    count: u32,
+}
+
+from_args!(Give { what, count });
+
+#[test]
+fn typed_args() {
+    let cm = CommandMatch::new("topy", "give", vec!["treats", "3"]);
+    assert_eq!(cm.arg::<String>(0), Ok("treats".to_string()));
+    assert_eq!(cm.arg::<u32>(1), Ok(3));
+    assert_eq!(cm.parse::<(String, u8)>(), Ok(("treats".to_string(), 3)));
+
+    let give = cm.parse::<Give>().unwrap();
+    assert_eq!((give.what.as_str(), give.count), ("treats", 3));
+
+    assert_eq!(cm.arg::<u32>(0).unwrap_err().value, Some("treats".to_string()));
+    assert_eq!(cm.arg::<u32>(2), Err(ArgError { index: 2, value: None, reason: "missing".to_string() }));
+    assert_eq!(cm.parse::<(String,)>().unwrap_err().index, 1);
+    assert_eq!(Vec::<u32>::from_args(&["1", "2", "3"]), Ok(vec![1, 2, 3]));
+    assert!(Give::from_args(&["treats", "lots"]).is_err());
+}
+
+#[test]
+fn usage() {
+    let shape = Shape::grammar(&["{self}, {cmd} {what} [{count:int}][!]", "{self}.{cmd}({things:quoted...})"]).unwrap();
+    assert_eq!(shape.usage("topy", "give"), vec!["topy, give <what> [<count>][!]", "topy.give(<things>...)"]);
+}
+
+#[test]
+fn nary_invoke() {
+    let shape = Shape::nary_invoke();
+    assert_eq!(args(&shape, vec!["remember"], "topy.remember('a', 'b', 'c')"), Some(vec!["a", "b", "c"]));
+    assert_eq!(args(&shape, vec!["remember"], "topy.remember(x, 'y z' 2.5)"), Some(vec!["x", "y z", "2.5"]));
+    assert_eq!(args(&shape, vec!["remember"], "topy->remember"), Some(vec![]));
+
+    let cm = shape.try_match("topy", &vec!["remember"], r#"topy.remember("say \"hi\"\n", 'it\s')"#).unwrap();
+    assert_eq!(cm.args, vec![r#"say \"hi\"\n"#, r#"it\s"#]);
+    assert_eq!((cm.value(0), cm.value(1), cm.value(2)), (Some("say \"hi\"\n"), Some("it\\s"), None));
+}
+
+#[test]
+fn rest_order() {
+    let shape = Shape::rest_order();
+    assert_eq!(args(&shape, vec!["say"], "topy, say hi, all (twice)!"), Some(vec!["hi, all (twice)!"]));
+    assert_eq!(args(&shape, vec!["say"], "topy, say"), None);
+    assert_eq!(args(&shape, vec!["say"], "topy, say roses are red\nviolets are blue"), Some(vec!["roses are red\nviolets are blue"]));
+}
+
+#[test]
+fn referents() {
+    let referent = Referent { aliases: vec!["tp".to_string(), "good dog".to_string()], ignore_case: true, typos: 1 };
+    assert_eq!(referent.matches("topy", "topy"), Some("topy"));
+    assert_eq!(referent.matches("topy", "Topy"), Some("topy"));
+    assert_eq!(referent.matches("topy", "toppy"), Some("topy"));
+    assert_eq!(referent.matches("topy", "TP"), Some("tp"));
+    assert_eq!(referent.matches("topy", "Good Dog"), Some("good dog"));
+    assert_eq!(referent.matches("topy", "tomy"), Some("topy"));
+    // too short for typos
+    assert_eq!(referent.matches("topy", "t"), None);
+    assert_eq!(referent.matches("topy", "tq"), None);
+    assert_eq!(referent.matches("topy", "topyy"), Some("topy"));
+    assert_eq!(referent.matches("topy", "tpy"), Some("topy"));
+    assert_eq!(referent.matches("topy", "rex"), None);
+    assert_eq!(Referent::default().matches("topy", "Topy"), None);
+
+    let shape = Shape::order();
+    let cm = shape.try_match_with("topy", &referent, &Lexicon::default(), &["sit"], "Good  Dog, sit!").unwrap();
+    assert_eq!((cm.referent, cm.alias.as_str()), ("Good  Dog", "good dog"));
+}
+
+#[test]
+fn lexicons() {
+    let mut lexicon = Lexicon::default();
+    lexicon.ignore_case = true;
+    lexicon.stem = true;
+    lexicon.synonyms("pet", vec!["pat", "scratch"]);
+    lexicon.translate("fr", "pet", vec!["caresse"]);
+    lexicon.translate("fr", "roll over", vec!["roule"]);
+
+    let labels = ["sit", "pet", "roll over", "give", "fetch"];
+    for &(said, label) in &[("Sit", "sit"), ("pets", "pet"), ("petting", "pet"), ("pats", "pet"),
+                            ("Scratching", "pet"), ("rolls over", "roll over"), ("rolling  over", "roll over"),
+                            ("gives", "give"), ("giving", "give"), ("fetches", "fetch")] {
+        assert_eq!(lexicon.matches(&labels, said), Some(label), "{}", said);
+    }
+    assert_eq!(lexicon.matches(&labels, "caresse"), None);
+    assert_eq!(lexicon.matches(&labels, "sitter"), None);
+
+    lexicon.locale = Some("fr".to_string());
+    assert_eq!(lexicon.matches(&labels, "caresse"), Some("pet"));
+    assert_eq!(lexicon.matches(&labels, "roule"), Some("roll over"));
+    assert_eq!(lexicon.matches(&labels, "pat"), Some("pet"));
+
+    assert_eq!(Lexicon::default().matches(&labels, "Sit"), None);
+}
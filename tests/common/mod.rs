@@ -0,0 +1,28 @@
+//! Fixtures shared between the integration tests
+
+#![allow(dead_code)]
+
+use zpet::*;
+use zpet::bot::builder::{Builder, NoHandlers, WithHandlers};
+use zpet::mock::MockZephyr;
+
+/// Sets up topy on `zio`: at home on the topy class, subscribed to
+/// games too, sitting when told and subscribing to wherever it's sent
+pub fn pet<E>(builder: Builder<E, NoHandlers>, zio: &MockZephyr) -> Builder<E, WithHandlers> {
+    builder
+        .with_zsig("woof")
+        .transport(zio.clone())
+        .sub_to_classes(vec!["topy", "games"])
+        .command(Shape::order(), Scope::Local, vec!["sit"], |state, notice, _| {
+            state.reply_to(notice, "*sits*").unwrap();
+        })
+        .command(Shape::unary_invoke(), Scope::Everywhere, vec!["go"], |state, _, cm| {
+            state.subscribe(Triplet::of_class(cm.args[0])).unwrap();
+            state.move_to(Triplet::of_instance(cm.args[0], "home"));
+            state.reply_here("*arrives*").unwrap();
+        })
+}
+
+pub fn pet_bot(zio: &MockZephyr) -> Bot {
+    pet(Bot::build("topy", ("topy", "home")), zio).build().unwrap()
+}
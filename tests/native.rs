@@ -0,0 +1,197 @@
+extern crate zpet;
+
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use zpet::*;
+use zpet::native::*;
+
+fn packet() -> Packet {
+    let uid = Uid { addr: Ipv4Addr::new(18, 9, 22, 69), secs: 0x5A000000, usecs: 42 };
+    let mut packet = Packet::new(Kind::Acked, uid, 0x1234);
+    packet.class = "topy".to_string();
+    packet.instance = "home".to_string();
+    packet.sender = "alice@ATHENA.MIT.EDU".to_string();
+    packet.message = b"woof\0topy, sit!\0".to_vec();
+    packet
+}
+
+#[test]
+fn encodes_header_fields() {
+    let bytes = packet().encode();
+    let fields = bytes.split(|&b| b == 0)
+        .map(|f| String::from_utf8(f.to_vec()).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(fields[0], "ZEPH0.2");
+    assert_eq!(fields[1], "0x00000011");
+    assert_eq!(fields[2], "0x00000002");
+    assert_eq!(fields[3], "0x12091645 0x5A000000 0x0000002A");
+    assert_eq!(fields[4], "0x1234");
+    assert_eq!(fields[8], "topy");
+    assert_eq!(fields[11], "alice@ATHENA.MIT.EDU");
+    assert_eq!(&fields[17..], &["woof", "topy, sit!", ""]);
+}
+
+#[test]
+fn decodes_what_it_encodes() {
+    let mut original = packet();
+    original.other_fields = vec!["extra".to_string()];
+    assert_eq!(Packet::decode(&original.encode()), Ok(original));
+}
+
+#[test]
+fn rejects_malformed_packets() {
+    assert_eq!(Packet::decode(b"ZEPH0.1\0"), Err(DecodeError::BadVersion("ZEPH0.1".to_string())));
+    assert_eq!(Packet::decode(b"ZEPH0.2\0"), Err(DecodeError::MissingField("num_fields")));
+
+    let mut bytes = packet().encode();
+    bytes[20] = b'Z';
+    assert!(Packet::decode(&bytes).is_err());
+}
+
+#[test]
+fn decodes_out_of_range_usecs() {
+    let mut original = packet();
+    original.uid.usecs = u32::MAX;
+    let decoded = Packet::decode(&original.encode()).unwrap();
+    assert_eq!(decoded.uid.usecs, u32::MAX);
+
+    let notice = decoded.to_notice();
+    let expected = UNIX_EPOCH + Duration::from_secs(0x5A000000) + Duration::from_micros(u64::from(u32::MAX));
+    assert_eq!(notice.date(), Some(expected));
+}
+
+#[test]
+fn converts_to_notice() {
+    let notice = packet().to_notice();
+    assert_eq!(notice.class, "topy");
+    assert_eq!(notice.zsig, "woof");
+    assert_eq!(notice.body, vec!["topy, sit!"]);
+    assert!(!notice.is_auth());
+}
+
+/// Stands in for zhm and the server: acknowledges everything it is
+/// sent, then delivers a single notice to the client
+fn fake_hostmanager(acks: usize) -> (SocketAddr, mpsc::Receiver<Packet>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        let mut client = None;
+        for _ in 0..acks {
+            let (len, from) = socket.recv_from(&mut buffer).unwrap();
+            let received = Packet::decode(&buffer[..len]).unwrap();
+            socket.send_to(&received.ack(Kind::HmAck).encode(), from).unwrap();
+
+            let mut servack = received.ack(Kind::ServAck);
+            servack.message = b"SENT\0".to_vec();
+            socket.send_to(&servack.encode(), from).unwrap();
+
+            client = Some(from);
+            tx.send(received).unwrap();
+        }
+
+        let client = client.unwrap();
+        socket.send_to(&packet().encode(), client).unwrap();
+        let (len, _) = socket.recv_from(&mut buffer).unwrap();
+        tx.send(Packet::decode(&buffer[..len]).unwrap()).unwrap();
+    });
+
+    (addr, rx)
+}
+
+#[test]
+fn talks_to_hostmanager() {
+    let (addr, rx) = fake_hostmanager(3);
+
+    let mut zio = NativeZephyr::connect_to(addr, "topy@ATHENA.MIT.EDU", vec![Triplet::of_class("topy")]).unwrap();
+
+    let sub = rx.recv().unwrap();
+    assert_eq!(sub.class, "ZEPHYR_CTL");
+    assert_eq!(sub.opcode, "SUBSCRIBE_NODEFS");
+    assert_eq!(sub.port, zio.port().unwrap());
+    assert_eq!(sub.message, b"topy\0*\0\0".to_vec());
+
+    zio.subscribe(Triplet::new("games", "chess", "topy@ATHENA.MIT.EDU")).unwrap();
+    assert_eq!(rx.recv().unwrap().message, b"games\0chess\0topy@ATHENA.MIT.EDU\0".to_vec());
+    assert_eq!(zio.subs().len(), 2);
+
+    let notice = Notice::new_outgoing("", "topy", "home", "topy", "woof", "*sits*");
+    zio.send(&notice).unwrap();
+    let sent = rx.recv().unwrap();
+    assert_eq!(sent.kind, Kind::Acked);
+    assert_eq!(sent.class, "topy");
+    assert_eq!(sent.message, b"woof\0*sits*\0".to_vec());
+
+    let received = zio.read().unwrap();
+    assert_eq!(received.sender, "alice@ATHENA.MIT.EDU");
+    assert_eq!(received.body, vec!["topy, sit!"]);
+
+    let ack = rx.recv().unwrap();
+    assert_eq!(ack.kind, Kind::ClientAck);
+    assert_eq!(ack.uid, packet().uid);
+}
+
+#[test]
+fn fragments_large_notices() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        let client = loop {
+            let (len, from) = socket.recv_from(&mut buffer).unwrap();
+            let received = Packet::decode(&buffer[..len]).unwrap();
+            socket.send_to(&received.ack(Kind::HmAck).encode(), from).unwrap();
+            let mut servack = received.ack(Kind::ServAck);
+            servack.message = b"SENT\0".to_vec();
+            socket.send_to(&servack.encode(), from).unwrap();
+
+            let (offset, total) = received.fragment().unwrap();
+            let done = offset + received.message.len() == total;
+            tx.send((len, received)).unwrap();
+            if done {
+                break from
+            }
+        };
+
+        let whole = packet();
+        let mut first = whole.clone();
+        first.multinotify = format!("0/{}", whole.message.len());
+        first.message = whole.message[..8].to_vec();
+        let mut second = whole.clone();
+        second.uid.usecs += 1;
+        second.multinotify = format!("8/{}", whole.message.len());
+        second.message = whole.message[8..].to_vec();
+
+        socket.send_to(&second.encode(), client).unwrap();
+        socket.send_to(&first.encode(), client).unwrap();
+    });
+
+    let mut zio = NativeZephyr::connect_to(addr, "topy@ATHENA.MIT.EDU", vec![]).unwrap();
+    let body = "woof ".repeat(400);
+    let notice = Notice::new_outgoing("", "topy", "home", "topy", "woof", &body);
+    zio.send(&notice).unwrap();
+
+    let fragments = rx.iter().collect::<Vec<_>>();
+    assert!(fragments.len() > 1);
+    let mut message = vec![];
+    for &(len, ref fragment) in &fragments {
+        assert!(len <= MAX_PACKET_LEN);
+        assert_eq!(fragment.multiuid, fragments[0].1.uid);
+        assert_eq!(fragment.fragment().unwrap().0, message.len());
+        message.extend_from_slice(&fragment.message);
+    }
+    assert!(message == format!("woof\0{}\0", notice.body.join("\n")).into_bytes());
+
+    let received = zio.read().unwrap();
+    assert_eq!(received.zsig, "woof");
+    assert_eq!(received.body, vec!["topy, sit!"]);
+    assert_eq!(received.date(), Some(packet().uid.time()));
+}
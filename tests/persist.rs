@@ -0,0 +1,120 @@
+#![cfg(feature = "persist")]
+
+#[macro_use] extern crate serde;
+extern crate zpet;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+mod common;
+
+use zpet::*;
+use zpet::mock::{self, MockZephyr};
+
+#[derive(Serialize, Deserialize)]
+struct Pet {
+    treats: u32,
+}
+
+fn save_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("zpet-{}-{}.json", name, process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn pet_bot(zio: &MockZephyr, path: &PathBuf) -> Bot<Pet> {
+    common::pet(Bot::build("topy", ("topy", "home")).with_extra(Pet { treats: 0 }), zio)
+        .catch_signals(false)
+        .persist_to(path)
+        .command(Shape::order(), Scope::Everywhere, vec!["treat"], |state, _, _| {
+            state.extra_mut().treats += 1;
+        })
+        .command(Shape::unary_invoke(), Scope::Everywhere, vec!["leave"], |state, _, cm| {
+            state.unsubscribe(&Triplet::of_class(cm.args[0])).unwrap();
+        })
+        .command(Shape::order(), Scope::Everywhere, vec!["sleep"], |state, _, _| {
+            state.shutdown();
+        })
+        .build().unwrap()
+}
+
+#[test]
+fn starts_fresh_without_a_save() {
+    let path = save_path("fresh");
+    let bot = pet_bot(&MockZephyr::new(vec![]), &path);
+
+    assert_eq!(bot.state.extra_ref().treats, 0);
+    assert_eq!(bot.state.location(), Triplet::of_instance("topy", "home"));
+    assert!(!path.exists());
+}
+
+#[test]
+fn restores_state_location_and_subs() {
+    let path = save_path("restore");
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio, &path);
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, treat!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy.go(park)"));
+    bot.tick(mock::incoming("park", "home", "alice", "topy, treat!"));
+    bot.state.save().unwrap();
+    drop(bot);
+
+    let zio = MockZephyr::new(vec![]);
+    let bot = pet_bot(&zio, &path);
+    assert_eq!(bot.state.extra_ref().treats, 2);
+    assert_eq!(bot.state.location(), Triplet::of_instance("park", "home"));
+    assert!(bot.state.subs().contains(&Triplet::of_class("park")));
+    assert!(bot.state.subs().contains(&Triplet::of_class("topy")));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn remembers_leaving_built_in_subs() {
+    let path = save_path("leave");
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio, &path);
+    bot.tick(mock::incoming("topy", "home", "alice", "topy.leave(games)"));
+    bot.state.save().unwrap();
+    drop(bot);
+
+    let bot = pet_bot(&MockZephyr::new(vec![]), &path);
+    assert_eq!(*bot.state.subs(), vec![Triplet::of_class("topy")]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn saves_on_shutdown() {
+    let path = save_path("shutdown");
+
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = pet_bot(&zio, &path);
+    zio.push(mock::incoming("topy", "home", "alice", "topy, treat!"));
+    zio.push(mock::incoming("topy", "home", "alice", "topy, sleep!"));
+    bot.run().unwrap();
+
+    let bot = pet_bot(&MockZephyr::new(vec![]), &path);
+    assert_eq!(bot.state.extra_ref().treats, 1);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rejects_corrupt_saves() {
+    let path = save_path("corrupt");
+    fs::write(&path, "{ not json").unwrap();
+
+    let result = Bot::build("topy", ("topy", "home"))
+        .with_extra(Pet { treats: 0 })
+        .transport(MockZephyr::new(vec![]))
+        .persist_to(&path)
+        .build();
+    assert!(result.is_err());
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,83 @@
+extern crate libc;
+extern crate zpet;
+
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use zpet::Cron;
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// `time` in the local timezone, which cron expressions are in
+fn local(time: SystemTime) -> libc::tm {
+    let secs = secs(time) as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    assert!(!unsafe { libc::localtime_r(&secs, &mut tm) }.is_null());
+    tm
+}
+
+#[test]
+fn parses_cron_fields() {
+    assert!(Cron::parse("* * * * *").is_ok());
+    assert!(Cron::parse("*/15 9-17 * * 1-5").is_ok());
+    assert!(Cron::parse("0,30 0 1 1,6 0,7").is_ok());
+    assert!(Cron::parse("5/10 * * * *").is_ok());
+}
+
+#[test]
+fn rejects_malformed_cron() {
+    assert!(Cron::parse("* * * *").is_err());
+    assert!(Cron::parse("60 * * * *").is_err());
+    assert!(Cron::parse("* 24 * * *").is_err());
+    assert!(Cron::parse("* * 0 * *").is_err());
+    assert!(Cron::parse("* * * 13 *").is_err());
+    assert!(Cron::parse("*/0 * * * *").is_err());
+    assert!(Cron::parse("5-1 * * * *").is_err());
+    assert!(Cron::parse("a * * * *").is_err());
+}
+
+#[test]
+fn every_minute_is_the_next_minute() {
+    let cron = Cron::parse("* * * * *").unwrap();
+    let now = UNIX_EPOCH + Duration::from_secs(1_500_000_030);
+
+    let next = cron.next_after(now).unwrap();
+    assert_eq!(secs(next), 1_500_000_060);
+
+    // strictly after, even on a minute boundary
+    assert_eq!(secs(cron.next_after(next).unwrap()), 1_500_000_120);
+}
+
+#[test]
+fn steps_skip_minutes() {
+    let cron = Cron::parse("*/15 * * * *").unwrap();
+    let now = SystemTime::now();
+
+    let next = cron.next_after(now).unwrap();
+    assert!(next > now);
+    assert!(next <= now + Duration::from_secs(15 * 60));
+    assert_eq!(local(next).tm_min % 15, 0);
+    assert_eq!(local(next).tm_sec, 0);
+}
+
+#[test]
+fn stepped_days_restrict_like_a_star() {
+    // as in cron, "*/2" is unrestricted for the purposes of combining
+    // days of the month and of the week, so both must match
+    let cron = Cron::parse("0 0 */2 * 1").unwrap();
+    let mut time = SystemTime::now();
+    for _ in 0..10 {
+        time = cron.next_after(time).unwrap();
+        let tm = local(time);
+        assert_eq!(tm.tm_wday, 1);
+        assert_eq!(tm.tm_mday % 2, 1);
+    }
+}
+
+#[test]
+fn impossible_dates_never_match() {
+    let cron = Cron::parse("0 0 31 2 *").unwrap();
+    assert_eq!(cron.next_after(SystemTime::now()), None);
+}
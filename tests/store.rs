@@ -0,0 +1,123 @@
+extern crate zpet;
+
+use zpet::*;
+use zpet::mock::{self, MockZephyr};
+use zpet::store::{Backend, MemoryBackend, Store};
+
+fn exercise(store: &Store) {
+    let alice = store.user("alice");
+    assert_eq!(alice.get("pets").unwrap(), None);
+
+    alice.set("pets", 3).unwrap();
+    alice.set("name", "topy").unwrap();
+    assert_eq!(alice.get_as::<u32>("pets").unwrap(), Some(3));
+    assert_eq!(alice.keys().unwrap(), vec!["name", "pets"]);
+    assert!(alice.get_as::<u32>("name").is_err());
+
+    // namespaces are separate
+    assert_eq!(store.user("bob").get("pets").unwrap(), None);
+    assert_eq!(store.global().get("pets").unwrap(), None);
+
+    assert_eq!(alice.update("pets", |n: Option<u32>| n.unwrap_or(0) + 1).unwrap(), 4);
+
+    alice.remove("name").unwrap();
+    assert_eq!(alice.keys().unwrap(), vec!["pets"]);
+}
+
+#[test]
+fn memory_backend() {
+    exercise(&Store::new(MemoryBackend::new()));
+}
+
+#[test]
+fn triplet_scopes_ignore_class_case() {
+    let store = Store::default();
+    store.triplet(&Triplet::of_instance("Topy", "home")).set("visits", 1).unwrap();
+
+    assert_eq!(store.triplet(&Triplet::of_instance("topy", "home")).get("visits").unwrap(),
+               Some("1".to_string()));
+    assert_eq!(store.triplet(&Triplet::of_class("topy")).get("visits").unwrap(), None);
+}
+
+#[test]
+fn commands_share_the_store() {
+    let zio = MockZephyr::new(vec![]);
+    let mut bot = Bot::build("topy", ("topy", "home"))
+        .transport(zio.clone())
+        .sub_to_class("topy")
+        .command(Shape::order(), Scope::Local, vec!["pet"], |state, notice, _| {
+            let pets = state.store().user(&notice.sender)
+                .update("pets", |n: Option<u32>| n.unwrap_or(0) + 1).unwrap();
+            state.reply_to(notice, &format!("*has been pet {} times by {}*", pets, notice.sender)).unwrap();
+        })
+        .build().unwrap();
+
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, pet!"));
+    bot.tick(mock::incoming("topy", "home", "bob", "topy, pet!"));
+    bot.tick(mock::incoming("topy", "home", "alice", "topy, pet!"));
+
+    assert_eq!(zio.sent()[2].body, vec!["*has been pet 2 times by alice*"]);
+    assert_eq!(bot.state.store().user("bob").get_as::<u32>("pets").unwrap(), Some(1));
+}
+
+#[test]
+fn custom_backends() {
+    struct Counting(MemoryBackend, usize);
+
+    impl Backend for Counting {
+        fn get(&self, namespace: &str, key: &str) -> Result<Option<String>> {
+            self.0.get(namespace, key)
+        }
+        fn set(&mut self, namespace: &str, key: &str, value: &str) -> Result<()> {
+            self.1 += 1;
+            self.0.set(namespace, key, value)
+        }
+        fn remove(&mut self, namespace: &str, key: &str) -> Result<()> {
+            self.0.remove(namespace, key)
+        }
+        fn keys(&self, namespace: &str) -> Result<Vec<String>> {
+            self.0.keys(namespace)
+        }
+    }
+
+    let bot = Bot::build("topy", ("topy", "home"))
+        .transport(MockZephyr::new(vec![]))
+        .store(Counting(MemoryBackend::new(), 0))
+        .build().unwrap();
+    exercise(bot.state.store());
+}
+
+#[cfg(feature = "persist")]
+#[test]
+fn json_backend_survives_reopening() {
+    use std::{env, fs, process};
+    use zpet::store::JsonBackend;
+
+    let path = env::temp_dir().join(format!("zpet-store-{}.json", process::id()));
+    let _ = fs::remove_file(&path);
+
+    exercise(&Store::new(JsonBackend::open(&path).unwrap()));
+
+    let store = Store::new(JsonBackend::open(&path).unwrap());
+    assert_eq!(store.user("alice").get_as::<u32>("pets").unwrap(), Some(4));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_backend_survives_reopening() {
+    use std::{env, fs, process};
+    use zpet::store::SqliteBackend;
+
+    let path = env::temp_dir().join(format!("zpet-store-{}.db", process::id()));
+    let _ = fs::remove_file(&path);
+
+    exercise(&Store::new(SqliteBackend::open(&path).unwrap()));
+
+    let store = Store::new(SqliteBackend::open(&path).unwrap());
+    assert_eq!(store.user("alice").get_as::<u32>("pets").unwrap(), Some(4));
+    assert_eq!(store.user("alice").keys().unwrap(), vec!["pets"]);
+
+    fs::remove_file(&path).unwrap();
+}
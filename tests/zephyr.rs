@@ -0,0 +1,120 @@
+extern crate zpet;
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process;
+use std::time::{Duration, UNIX_EPOCH};
+
+use zpet::Triplet;
+use zpet::native::Kind;
+use zpet::zephyr::{FrameBuffer, FrameError, Zephyr, parse_frame};
+
+const NOTICE: &str = "personal\nclass: topy\ninstance: home\nsender: alice\nauth: yes\nsignature: Alice\nbody: topy, sit!\nbody: good dog\ndone\n";
+
+#[test]
+fn frames_split_and_merged_reads() {
+    let mut frames = FrameBuffer::new();
+    let bytes = NOTICE.as_bytes();
+
+    frames.extend(&bytes[..10]);
+    assert_eq!(frames.next_frame(), None);
+
+    // the rest of the first notice arrives with all of a second one
+    frames.extend(&bytes[10..]);
+    frames.extend(bytes);
+    let first = frames.next_frame().unwrap();
+    assert_eq!(first, &NOTICE[..NOTICE.len() - 5]);
+    assert_eq!(frames.next_frame(), Some(first));
+    assert_eq!(frames.next_frame(), None);
+}
+
+#[test]
+fn sentinel_must_start_a_line() {
+    let mut frames = FrameBuffer::new();
+    frames.extend(b"class: topy\nbody: all done\nbody: undone\n");
+    assert_eq!(frames.next_frame(), None);
+
+    frames.extend(b"done\n");
+    assert_eq!(frames.next_frame().unwrap(), "class: topy\nbody: all done\nbody: undone\n");
+}
+
+#[test]
+fn invalid_utf8_is_replaced() {
+    let mut frames = FrameBuffer::new();
+    frames.extend(b"class: t\xffpy\ndone\n");
+    assert_eq!(frames.next_frame().unwrap(), "class: t\u{fffd}py\n");
+}
+
+#[test]
+fn parses_fields() {
+    let notice = parse_frame(&NOTICE[..NOTICE.len() - 5]).unwrap();
+    assert_eq!(notice.class, "topy");
+    assert_eq!(notice.instance, "home");
+    assert_eq!(notice.sender, "alice");
+    assert_eq!(notice.zsig, "Alice");
+    assert_eq!(notice.body, vec!["topy, sit!", "good dog"]);
+    assert!(notice.is_auth());
+}
+
+#[test]
+fn rejects_malformed_frames() {
+    assert_eq!(parse_frame("class: topy\nsender: alice\ngarbage\n").err(),
+               Some(FrameError::MalformedLine("garbage".to_string())));
+    assert_eq!(parse_frame("sender: alice\n").err(), Some(FrameError::MissingField("class")));
+    assert_eq!(parse_frame("class: topy\n").err(), Some(FrameError::MissingField("sender")));
+}
+
+#[test]
+fn parses_timestamps() {
+    let date = |time: &str, date: &str| {
+        let frame = format!("class: topy\nsender: alice\ntime: {}\ndate: {}\n", time, date);
+        parse_frame(&frame).unwrap().date()
+    };
+
+    let first = date("21:49:08", "Wed Jan 10 2018").unwrap();
+    let second = date("22:49:09", "Thu Jan 11 2018").unwrap();
+    assert_eq!(second.duration_since(first).unwrap(), Duration::from_secs(25 * 3600 + 1));
+
+    let since_epoch = first.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert!(since_epoch > 1515456000 && since_epoch < 1515715200);
+
+    assert_eq!(date("21:49", "Wed Jan 10 2018"), None);
+    assert_eq!(date("21:49:08", "yesterday"), None);
+}
+
+#[test]
+fn parses_recipient_and_metadata() {
+    let notice = parse_frame("personal\nclass: message\ninstance: personal\nsender: alice@ATHENA.MIT.EDU\nrecipient: topy@ATHENA.MIT.EDU\nkind: acked\nport: 4242\n").unwrap();
+    assert_eq!(notice.recipient, "topy@ATHENA.MIT.EDU");
+    assert!(notice.is_personal());
+    assert!(notice.was_sent_to(&Triplet::personal()));
+
+    let data = notice.incoming_data.unwrap();
+    assert_eq!(data.realm, "ATHENA.MIT.EDU");
+    assert_eq!(data.port, Some(4242));
+    assert_eq!(data.kind, Some(Kind::Acked));
+
+    let notice = parse_frame("class: message\ninstance: personal\nsender: alice@ATHENA.MIT.EDU\nrecipient: *\n").unwrap();
+    assert_eq!(notice.recipient, "");
+    assert!(!notice.is_personal());
+    assert!(!notice.was_sent_to(&Triplet::personal()));
+    assert_eq!(notice.incoming_data.unwrap().realm, "ATHENA.MIT.EDU");
+}
+
+#[test]
+fn keeps_notices_printed_as_zwgc_starts() {
+    // a zwgc which prints its startup notice between two real ones
+    let bin = env::temp_dir().join(format!("zpet-zwgc-{}", process::id()));
+    fs::create_dir_all(&bin).unwrap();
+    let zwgc = bin.join("zwgc");
+    fs::write(&zwgc, "#!/bin/sh\nprintf 'class: topy\\nsender: alice\\ndone\\nready\\ndone\\nclass: topy\\nsender: bob\\ndone\\n'\nexec sleep 60\n").unwrap();
+    fs::set_permissions(&zwgc, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut zio = Zephyr::with_zwgc(vec![Triplet::of_class("topy")], &zwgc).unwrap();
+    assert_eq!(zio.read().unwrap().sender, "alice");
+    assert_eq!(zio.read().unwrap().sender, "bob");
+
+    zio.close().unwrap();
+    fs::remove_dir_all(&bin).unwrap();
+}